@@ -0,0 +1,265 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use base64;
+use mvdb::Mvdb;
+use ring::constant_time::verify_slices_are_equal;
+use ring::rand::SecureRandom;
+use rocket::{self, Outcome, Rocket, State};
+use rocket::config::{Config, Environment};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket_contrib::{Json, Value};
+
+use api::health;
+use api::in_flight::InFlight;
+use api::negotiate::Body;
+use datetime_utils::ProveWhenTime;
+use errors as echain;
+use errors::ResultExt;
+use file_utils::create_secret_file;
+use key_types::{TimedPublicKey, RANDOM};
+use pub_key_storage::KeyDB;
+use settings::Settings;
+use status::{read_keystore, write_keystore, STATUS};
+
+pub const MOUNT_POINT: &str = "/admin";
+
+/// Read the admin token, generating it on first start
+pub fn load_or_create_token(path: &Path) -> echain::Result<String> {
+    if !path.exists() {
+        let mut raw = [0u8; 32];
+        RANDOM
+            .deref()
+            .fill(&mut raw[..])
+            .chain_err(|| "Failed to generate admin token")?;
+
+        let token = base64::encode_config(&raw[..], base64::URL_SAFE_NO_PAD);
+
+        create_secret_file(path)
+            .and_then(|mut f| f.write_all(token.as_bytes()))
+            .chain_err(|| "Failed to write admin token")?;
+    }
+
+    read_token(path)
+}
+
+pub fn read_token(path: &Path) -> echain::Result<String> {
+    let mut token = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut token))
+        .chain_err(|| format!("Failed to read admin token from {}", path.display()))?;
+
+    Ok(token.trim().to_string())
+}
+
+/// Managed state of the admin server
+pub struct AdminState {
+    token: String,
+    audit_log: PathBuf,
+}
+
+impl AdminState {
+    /// Append an entry to the audit log. A failure to log is reported,
+    /// but doesn't fail the request
+    fn audit(&self, remote: &str, action: &str, outcome: &str) {
+        let line = format!(
+            "{}\t{}\t{}\t{}\n",
+            ProveWhenTime::now().as_str(),
+            remote,
+            action,
+            outcome
+        );
+        print!("admin: {}", line);
+
+        let rslt = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log)
+            .and_then(|mut f| f.write_all(line.as_bytes()));
+
+        if let Err(e) = rslt {
+            println!("Failed to write admin audit log: {}", e);
+        }
+    }
+}
+
+/// Request guard for admin endpoints. Only loopback clients presenting
+/// the admin token get through, and rejected attempts are audited too
+pub struct Admin<'r> {
+    state: State<'r, AdminState>,
+    remote: String,
+}
+
+impl<'r> Admin<'r> {
+    fn audit(&self, action: &str, outcome: &str) {
+        self.state.audit(&self.remote, action, outcome);
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let state = match request.guard::<State<AdminState>>() {
+            Outcome::Success(s) => s,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let remote = request.remote();
+        let remote_str = remote.map_or("unknown".into(), |a| a.to_string());
+        let action = format!("{} {}", request.method(), request.uri().path());
+
+        if !remote.map_or(false, |a| a.ip().is_loopback()) {
+            state.audit(&remote_str, &action, "denied: not a loopback address");
+            return Outcome::Failure((Status::Forbidden, ()));
+        }
+
+        let presented = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| if h.starts_with("Bearer ") { Some(h[7..].trim()) } else { None });
+
+        let valid = match presented {
+            Some(token) => verify_slices_are_equal(token.as_bytes(), state.token.as_bytes()).is_ok(),
+            None => false,
+        };
+
+        if !valid {
+            state.audit(&remote_str, &action, "denied: bad token");
+            return Outcome::Failure((Status::Unauthorized, ()));
+        }
+
+        Outcome::Success(Admin {
+            state: state,
+            remote: remote_str,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct RotateRequest {
+    /// Also revoke the key being replaced, giving this reason
+    #[serde(default)]
+    pub revoke: Option<String>,
+}
+
+/// The `/health` report, plus the keys and revocations
+#[get("/status")]
+pub fn status(
+    admin: Admin,
+    keydb: State<Mvdb<KeyDB>>,
+    settings: State<Settings>,
+) -> Result<Json<Value>, echain::Error> {
+    let mut body = health::report(&keydb, &settings).body;
+
+    let keys = read_keystore(&keydb, |db| -> echain::Result<Value> {
+        Ok(json!({
+            "current": db.current_public_key()?,
+            "next": db.next_public_key(),
+            "revocations": db.revocations(),
+        }))
+    })??;
+    body["keys"] = keys;
+
+    admin.audit("status", "ok");
+
+    Ok(Json(body))
+}
+
+/// Replace the signing key now, optionally revoking the old one
+#[post("/rotate", data = "<req>")]
+pub fn rotate(
+    admin: Admin,
+    req: Body<RotateRequest>,
+    keydb: State<Mvdb<KeyDB>>,
+) -> Result<Json<TimedPublicKey>, echain::Error> {
+    let action = match req.revoke {
+        Some(ref reason) => format!("rotate, revoking: {:?}", reason),
+        None => "rotate".into(),
+    };
+
+    let rslt = write_keystore(&keydb, |db| db.force_rotate(req.revoke.clone()))
+        .and_then(|key| key);
+
+    match rslt {
+        Ok(key) => {
+            admin.audit(&action, &format!("ok, new key {}", key.time().as_str()));
+            Ok(Json(key))
+        }
+        Err(e) => {
+            admin.audit(&action, &format!("failed: {}", e));
+            Err(e)
+        }
+    }
+}
+
+fn set_paused(admin: &Admin, paused: bool) -> Json<Value> {
+    let was = STATUS.set_signing_paused(paused);
+
+    admin.audit(
+        if paused { "pause" } else { "resume" },
+        if was == paused { "ok, unchanged" } else { "ok" },
+    );
+
+    Json(json!({ "paused": paused }))
+}
+
+/// Refuse to sign until resumed. Verification and key lookups continue
+#[post("/pause")]
+pub fn pause(admin: Admin) -> Json<Value> {
+    set_paused(&admin, true)
+}
+
+#[post("/resume")]
+pub fn resume(admin: Admin) -> Json<Value> {
+    set_paused(&admin, false)
+}
+
+#[error(401)]
+fn unauthorized() -> Json<Value> {
+    Json(json!({
+        "status": "failure",
+        "message": "A valid admin token is required",
+    }))
+}
+
+#[error(403)]
+fn forbidden() -> Json<Value> {
+    Json(json!({
+        "status": "failure",
+        "message": "The admin API only accepts local connections",
+    }))
+}
+
+/// A second Rocket instance for the admin API, listening on localhost
+/// only. Rocket 0.3 can't listen on a Unix socket, so the loopback check
+/// in `Admin` and the token are what keep it private
+pub fn setup_admin_rocket(
+    keydb: Mvdb<KeyDB>,
+    settings: Settings,
+    token: String,
+) -> echain::Result<Rocket> {
+    let config = Config::build(Environment::active().unwrap_or(Environment::Development))
+        .address("127.0.0.1")
+        .port(settings.admin.port)
+        .finalize()
+        .chain_err(|| "Invalid admin server configuration")?;
+
+    let state = AdminState {
+        token: token,
+        audit_log: settings.admin.audit_log.clone(),
+    };
+
+    Ok(
+        rocket::custom(config, true)
+            .mount(MOUNT_POINT, routes![status, rotate, pause, resume])
+            .catch(errors![unauthorized, forbidden])
+            .attach(InFlight)
+            .manage(state)
+            .manage(keydb)
+            .manage(settings),
+    )
+}
@@ -0,0 +1,146 @@
+use std::io::Cursor;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::request::Request;
+use rocket::response::Response;
+
+use settings::CorsSettings;
+
+/// Adds CORS headers to API responses, and answers preflight `OPTIONS`
+/// requests. Preflights don't need a route of their own: they arrive
+/// here as a 404, which is replaced with an empty 204
+pub struct Cors {
+    settings: CorsSettings,
+}
+
+impl Cors {
+    pub fn new(settings: CorsSettings) -> Self {
+        Self { settings: settings }
+    }
+
+    fn allow_any_origin(&self) -> bool {
+        self.settings.allowed_origins.iter().any(|o| o == "*")
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.allow_any_origin() ||
+            self.settings
+                .allowed_origins
+                .iter()
+                .any(|o| o.eq_ignore_ascii_case(origin))
+    }
+
+    fn method_allowed(&self, method: &str) -> bool {
+        self.settings
+            .allowed_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    fn headers_allowed(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(|h| h.trim())
+            .filter(|h| !h.is_empty())
+            .all(|h| {
+                self.settings
+                    .allowed_headers
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(h))
+            })
+    }
+}
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        let origin = match request.headers().get_one("Origin") {
+            Some(o) if self.origin_allowed(o) => o.to_string(),
+            _ => return,
+        };
+
+        if self.allow_any_origin() {
+            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+        } else {
+            response.set_header(Header::new("Access-Control-Allow-Origin", origin));
+            response.set_header(Header::new("Vary", "Origin"));
+        }
+
+        let preflight_method = request.headers().get_one("Access-Control-Request-Method");
+        let is_preflight = request.method() == Method::Options && preflight_method.is_some();
+
+        if !is_preflight {
+            if !self.settings.expose_headers.is_empty() {
+                response.set_header(Header::new(
+                    "Access-Control-Expose-Headers",
+                    self.settings.expose_headers.join(", "),
+                ));
+            }
+            return;
+        }
+
+        let requested_headers = request
+            .headers()
+            .get_one("Access-Control-Request-Headers")
+            .unwrap_or("");
+
+        if !preflight_method.map_or(false, |m| self.method_allowed(m)) ||
+            !self.headers_allowed(requested_headers)
+        {
+            return;
+        }
+
+        response.set_header(Header::new(
+            "Access-Control-Allow-Methods",
+            self.settings.allowed_methods.join(", "),
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Allow-Headers",
+            self.settings.allowed_headers.join(", "),
+        ));
+        response.set_header(Header::new(
+            "Access-Control-Max-Age",
+            self.settings.max_age_secs.to_string(),
+        ));
+
+        // Replace the "no route" response with an empty success
+        if response.status() == Status::NotFound {
+            response.set_status(Status::NoContent);
+            response.remove_header("Content-Type");
+            response.set_sized_body(Cursor::new(Vec::new()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_and_headers() {
+        let mut settings = CorsSettings::default();
+        settings.allowed_origins = vec!["https://provewhen.io".into()];
+        let cors = Cors::new(settings);
+
+        assert!(cors.origin_allowed("https://provewhen.io"));
+        assert!(!cors.origin_allowed("https://evil.example"));
+
+        assert!(cors.method_allowed("post"));
+        assert!(!cors.method_allowed("DELETE"));
+
+        assert!(cors.headers_allowed("content-type, x-api-key"));
+        assert!(cors.headers_allowed(""));
+        assert!(!cors.headers_allowed("content-type, x-secret"));
+    }
+}
@@ -1,85 +1,239 @@
+use chrono::Duration;
 use mvdb::Mvdb;
 use rocket::State;
+use rocket::http::ContentType;
+use rocket::response::content::Content;
 use rocket_contrib::{Json, Value};
 
+use api::negotiate::{Body, Negotiated};
+use api::quota::ApiClient;
 use api::types::*;
 use errors as echain;
 use pub_key_storage::KeyDB;
+use metrics::METRICS;
+use settings::Settings;
+use status::{read_keystore, write_keystore, STATUS};
 use datetime_utils::ProveWhenTime;
+use key_types::{ReceiptToken, TokenFormat, VerifyMode};
 
 #[get("/hello", format = "application/json")]
 pub fn hello() -> Result<Json<String>, echain::Error> {
     Ok(Json("Hello!".into()))
 }
 
-#[post("/sign", format = "application/json", data = "<message>")]
+#[post("/sign", data = "<message>")]
 pub fn sign(
-    message: Json<SignRequest>,
+    _client: ApiClient,
+    message: Body<SignRequest>,
     keydb: State<Mvdb<KeyDB>>,
-) -> Result<Json<SignResponse>, echain::Error> {
-    let sgd = keydb.access_mut(|db| {
-        let signer = db.get_current();
-        signer.sign(ProveWhenTime::now(), &message.message)
+) -> Result<Negotiated<SignResponse>, echain::Error> {
+    let sgd = STATUS
+        .check_signing()
+        .and_then(|_| write_keystore(&keydb, |db| db.sign(&message.message)))
+        .and_then(|sgd| sgd);
+
+    Ok(Negotiated(METRICS.observe_sign("sign", sgd)?))
+}
+
+#[post("/sign/<format>", data = "<message>")]
+pub fn sign_token(
+    _client: ApiClient,
+    format: TokenFormat,
+    message: Body<SignRequest>,
+    keydb: State<Mvdb<KeyDB>>,
+) -> Result<ReceiptToken, echain::Error> {
+    let token = STATUS
+        .check_signing()
+        .and_then(|_| write_keystore(&keydb, |db| db.sign_token(&message.message, format)))
+        .and_then(|token| token);
+
+    Ok(METRICS.observe_sign("sign_token", token)?)
+}
+
+/// A signed reading of the server clock. The client nonce is covered by
+/// the signature, so a response can't be replayed to another client
+#[get("/time/<nonce>")]
+pub fn time(
+    _client: ApiClient,
+    nonce: String,
+    keydb: State<Mvdb<KeyDB>>,
+) -> Result<Negotiated<TimeResponse>, echain::Error> {
+    let rslt = STATUS
+        .check_signing()
+        .and_then(|_| write_keystore(&keydb, |db| db.sign_time(&nonce)))
+        .and_then(|rslt| rslt);
+
+    Ok(Negotiated(METRICS.observe_sign("time", rslt)?))
+}
+
+/// Whether the next key should be announced at `now`
+fn in_announce_window(now: &ProveWhenTime, settings: &Settings) -> bool {
+    let announce = Duration::seconds(settings.rotation.announce_secs);
+    settings.rotation.announce_secs > 0 && *now.next_period().inner() - announce <= *now.inner()
+}
+
+#[get("/key/current")]
+pub fn key_current(
+    keydb: State<Mvdb<KeyDB>>,
+    settings: State<Settings>,
+) -> Result<Negotiated<CurrentKeyResponse>, echain::Error> {
+    let now = ProveWhenTime::now();
+    let announce = in_announce_window(&now, &settings);
+
+    let stale = read_keystore(&keydb, |db| {
+        db.needs_rotation() || (announce && db.next_public_key().is_none())
     })?;
 
-    Ok(Json(sgd?))
+    // Only take the write lock (and write to disk) when something changes
+    if stale {
+        write_keystore(&keydb, |db| {
+            db.get_current();
+            if announce {
+                db.prepare_next();
+            }
+        })?;
+    }
+
+    let rslt = read_keystore(&keydb, |db| -> echain::Result<CurrentKeyResponse> {
+        let key = db.current_public_key()?;
+
+        Ok(CurrentKeyResponse {
+            expires: key.time().next_period(),
+            key: key,
+            next: if announce { db.next_public_key().cloned() } else { None },
+        })
+    })??;
+
+    Ok(Negotiated(rslt))
 }
 
-#[get("/key/time/<time>", format = "application/json")]
+#[get("/key/time/<time>")]
 pub fn key_time(
-    time: ProveWhenTime,
+    time: Result<ProveWhenTime, echain::Error>,
     keydb: State<Mvdb<KeyDB>>,
-) -> Result<Json<KeyResponse>, echain::Error> {
-    let rslt = keydb.access(|db| db.get_public_key_by_time(&time))??;
+) -> Result<Negotiated<KeyResponse>, echain::Error> {
+    let time = time?;
+    let rslt = read_keystore(&keydb, |db| db.get_public_key_by_time(&time))??;
 
-    Ok(Json(rslt))
+    Ok(Negotiated(rslt))
 }
 
-#[get("/key/time/<start>/<end>", format = "application/json")]
+#[get("/key/time/<start>/<end>")]
 pub fn key_time_range(
-    start: ProveWhenTime,
-    end: ProveWhenTime,
+    start: Result<ProveWhenTime, echain::Error>,
+    end: Result<ProveWhenTime, echain::Error>,
     keydb: State<Mvdb<KeyDB>>,
-) -> Result<Json<KeyRangeResponse>, echain::Error> {
-    let rslt: echain::Result<Vec<KeyResponse>> = keydb.access(|db| {
+) -> Result<Negotiated<KeyRangeResponse>, echain::Error> {
+    let (start, end) = (start?, end?);
+    let rslt: echain::Result<Vec<KeyResponse>> = read_keystore(&keydb, |db| {
         Ok(
             db.range(&start, &end)?
             .iter()
             .take(50) // limit to 50 responses
-            .cloned()
+            .map(|k| db.key_response(k))
             .collect(),
         )
     })?;
 
-    Ok(Json(KeyRangeResponse { keys: rslt? }))
+    Ok(Negotiated(KeyRangeResponse { keys: rslt? }))
 }
 
-#[post("/verify", format = "application/json", data = "<message>")]
-pub fn verify(
-    message: Json<VerifyRequest>,
-    keydb: State<Mvdb<KeyDB>>,
-) -> Result<Json<Value>, echain::Error> {
+/// Most keys returned in a single JWKS document
+const JWKS_LIMIT: usize = 50;
 
-    keydb.access(|db| {
-        db.verify(&message)
+fn jwks_window(
+    keydb: &Mvdb<KeyDB>,
+    start: &ProveWhenTime,
+    end: &ProveWhenTime,
+) -> Result<Content<Json<JwkSet>>, echain::Error> {
+    // Revoked keys are left out, so JOSE and COSE libraries refuse
+    // tokens signed with them, like `/verify` does
+    let window = read_keystore(&keydb, |db| {
+        db.window(start, end).map(|keys| {
+            keys.into_iter()
+                .filter(|&(ref key, _)| !db.is_revoked(key.public_key()))
+                .collect::<Vec<_>>()
+        })
     })??;
 
-    Ok(Json(json!({
-        "result": "ok"
-    })))
+    // Prefer the most recent keys if the window is too large
+    let skip = window.len().saturating_sub(JWKS_LIMIT);
+
+    let keys = window
+        .iter()
+        .skip(skip)
+        .map(|&(ref key, ref valid_until)| Jwk::from_timed_key(key, valid_until))
+        .collect::<echain::Result<Vec<Jwk>>>()?;
+
+    Ok(Content(
+        ContentType::new("application", "jwk-set+json"),
+        Json(JwkSet { keys: keys }),
+    ))
+}
+
+#[get("/jwks.json")]
+pub fn jwks(keydb: State<Mvdb<KeyDB>>) -> Result<Content<Json<JwkSet>>, echain::Error> {
+    let end = ProveWhenTime::now();
+    let start = ProveWhenTime::from_inner(*end.inner() - Duration::days(1));
+
+    jwks_window(&keydb, &start, &end)
+}
+
+#[get("/jwks/<start>/<end>")]
+pub fn jwks_range(
+    start: Result<ProveWhenTime, echain::Error>,
+    end: Result<ProveWhenTime, echain::Error>,
+    keydb: State<Mvdb<KeyDB>>,
+) -> Result<Content<Json<JwkSet>>, echain::Error> {
+    jwks_window(&keydb, &start?, &end?)
 }
 
+fn verify_receipt(
+    keydb: &Mvdb<KeyDB>,
+    receipt: &VerifyRequest,
+    mode: VerifyMode,
+) -> Result<Negotiated<Value>, echain::Error> {
+    let verified = read_keystore(keydb, |db| {
+        db.verify(receipt, mode)
+    }).and_then(|v| v);
 
-// use std::io;
-// use std::path::{Path, PathBuf};
-// use rocket::response::NamedFile;
+    METRICS.observe_verify(verified)?;
 
-// #[get("/")]
-// pub fn index() -> io::Result<NamedFile> {
-//     NamedFile::open("static/index.html")
-// }
+    // The receipt only proves the message was signed somewhere in this
+    // interval, not at the exact `timestamp`
+    let (not_before, not_after) = match receipt.interval() {
+        Some((start, end)) => (json!(start.as_str()), json!(end.as_str())),
+        None => (Value::Null, Value::Null),
+    };
+
+    Ok(Negotiated(json!({
+        "result": "ok",
+        "mode": match mode {
+            VerifyMode::Strict => "strict",
+            VerifyMode::Lenient => "lenient",
+        },
+        "timestamp": receipt.timestamp.as_str(),
+        "accuracy_ms": receipt.accuracy_ms,
+        "not_before": not_before,
+        "not_after": not_after,
+    })))
+}
+
+/// Verify a receipt, strictly unless it predates receipt versions
+#[post("/verify", data = "<message>")]
+pub fn verify(
+    message: Body<VerifyRequest>,
+    keydb: State<Mvdb<KeyDB>>,
+) -> Result<Negotiated<Value>, echain::Error> {
+    verify_receipt(&keydb, &message, VerifyMode::for_receipt(&message))
+}
+
+#[post("/verify/<mode>", data = "<message>")]
+pub fn verify_mode(
+    mode: VerifyMode,
+    message: Body<VerifyRequest>,
+    keydb: State<Mvdb<KeyDB>>,
+) -> Result<Negotiated<Value>, echain::Error> {
+    verify_receipt(&keydb, &message, mode)
+}
 
-// #[get("/<file..>", rank = 2)]
-// pub fn files(file: PathBuf) -> Option<NamedFile> {
-//     NamedFile::open(Path::new("static/").join(file)).ok()
-// }
@@ -0,0 +1,230 @@
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use mvdb::Mvdb;
+use rocket::State;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Failure, Responder, Response};
+use rocket::Outcome;
+use serde_json;
+
+use datetime_utils::ProveWhenTime;
+use errors as echain;
+use events::{KeyEvent, EVENTS};
+use pub_key_storage::KeyDB;
+use settings::Settings;
+use status::read_keystore;
+
+/// How often a comment is sent on an idle stream, so proxies and
+/// clients don't give up on the connection
+const KEEPALIVE_SECS: u64 = 15;
+
+/// Each open stream holds one of Rocket's worker threads
+static OPEN_STREAMS: AtomicUsize = ::std::sync::atomic::ATOMIC_USIZE_INIT;
+
+/// Resume point sent by reconnecting `EventSource` clients
+pub struct LastEventId(Option<ProveWhenTime>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for LastEventId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let since = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|id| ProveWhenTime::from_str(id).ok());
+
+        Outcome::Success(LastEventId(since))
+    }
+}
+
+/// A Server-Sent Events stream of `KeyEvent`s. Events replayed from the
+/// key database are sent first, followed by live events
+pub struct EventStream {
+    backlog: VecDeque<KeyEvent>,
+    live: Receiver<KeyEvent>,
+    replayed: HashSet<Vec<u8>>, // rendered, to skip them if they arrive live
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+fn render(event: &KeyEvent) -> Vec<u8> {
+    let data = serde_json::to_string(event).unwrap_or_else(|_| "{}".into());
+    format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.time().as_str(),
+        event.name(),
+        data
+    ).into_bytes()
+}
+
+impl EventStream {
+    /// Counts as an open stream until dropped
+    fn new(backlog: Vec<KeyEvent>, live: Receiver<KeyEvent>) -> Self {
+        EventStream {
+            replayed: backlog.iter().map(render).collect(),
+            backlog: backlog.into_iter().collect(),
+            live: live,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Wait for the next event to send, or `None` for a keepalive
+    fn next_event(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(event) = self.backlog.pop_front() {
+            return Ok(Some(render(&event)));
+        }
+
+        loop {
+            match self.live.recv_timeout(Duration::from_secs(KEEPALIVE_SECS)) {
+                Ok(event) => {
+                    let rendered = render(&event);
+
+                    // Published while the replay was read
+                    if self.replayed.remove(&rendered) {
+                        continue;
+                    }
+                    return Ok(Some(rendered));
+                }
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "event bus closed"))
+                }
+            }
+        }
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos >= self.buf.len() {
+            self.buf = match self.next_event()? {
+                Some(rendered) => rendered,
+                None => b": keepalive\n\n".to_vec(),
+            };
+            self.pos = 0;
+        }
+
+        let n = cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<'r> Responder<'r> for EventStream {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Ok(
+            Response::build()
+                .header(ContentType::new("text", "event-stream"))
+                .raw_header("Cache-Control", "no-cache")
+                .chunked_body(self, 1024)
+                .finalize(),
+        )
+    }
+}
+
+fn open_stream(
+    keydb: &Mvdb<KeyDB>,
+    settings: &Settings,
+    since: Option<ProveWhenTime>,
+) -> Result<EventStream, Failure> {
+    if OPEN_STREAMS.fetch_add(1, Ordering::SeqCst) >= settings.events.max_streams {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+        return Err(Failure(Status::ServiceUnavailable));
+    }
+
+    // Subscribe before reading history, so nothing falls in between. The
+    // overlap is skipped by `EventStream`
+    let live = EVENTS.subscribe();
+
+    let backlog = match since {
+        Some(ref since) => {
+            let replay: echain::Result<Vec<KeyEvent>> =
+                read_keystore(keydb, |db| db.events_after(since));
+
+            match replay {
+                Ok(events) => events,
+                Err(_) => {
+                    OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+                    return Err(Failure(Status::InternalServerError));
+                }
+            }
+        }
+        None => Vec::new(),
+    };
+
+    Ok(EventStream::new(backlog, live))
+}
+
+/// Live key events. `EventSource` clients reconnect with `Last-Event-ID`
+/// and receive every event they missed
+#[get("/key/events")]
+pub fn key_events(
+    last: LastEventId,
+    keydb: State<Mvdb<KeyDB>>,
+    settings: State<Settings>,
+) -> Result<EventStream, Failure> {
+    open_stream(&keydb, &settings, last.0)
+}
+
+/// Live key events, after replaying every event after `since`
+#[get("/key/events/<since>")]
+pub fn key_events_since(
+    since: Result<ProveWhenTime, echain::Error>,
+    keydb: State<Mvdb<KeyDB>>,
+    settings: State<Settings>,
+) -> Result<EventStream, Failure> {
+    let since = since.map_err(|_| Failure(Status::BadRequest))?;
+    open_stream(&keydb, &settings, Some(since))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use clock::ManualClock;
+    use super::*;
+
+    #[test]
+    fn resumed_stream_receives_gap() {
+        let clock = Arc::new(ManualClock::at("2018-03-01T10:15:00+00:00"));
+        let mut kdb = KeyDB::with_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+        let stored = serde_json::to_string(&kdb).unwrap();
+
+        // Restarted after a few hours down
+        clock.set(*ProveWhenTime::from_str("2018-03-01T13:20:00+00:00").unwrap().inner());
+        let mut kdb: KeyDB = serde_json::from_str(&stored).unwrap();
+        kdb.set_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+
+        let since = ProveWhenTime::from_str("2018-03-01T10:30:00+00:00").unwrap();
+        let (_tx, live) = channel();
+        OPEN_STREAMS.fetch_add(1, Ordering::SeqCst);
+        let mut stream = EventStream::new(kdb.events_after(&since), live);
+
+        let mut sent = String::new();
+        while !sent.contains("event: gap") {
+            let mut buf = [0u8; 256];
+            let n = stream.read(&mut buf).unwrap();
+            sent.push_str(::std::str::from_utf8(&buf[..n]).unwrap());
+            assert!(!sent.contains("keepalive"), "no gap event in the replay");
+        }
+        assert!(sent.contains("id: 2018-03-01T13:20:00+00:00"));
+    }
+}
@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+
+use rocket::State;
+use rocket::http::Header;
+use rocket::request::Request;
+use rocket::response::{self, NamedFile, Responder};
+
+use settings::FrontendSettings;
+
+/// Canonical path of the directory the frontend is served from
+pub struct FrontendRoot {
+    root: PathBuf,
+    max_age_secs: u64,
+}
+
+impl FrontendRoot {
+    pub fn new(settings: &FrontendSettings) -> ::std::io::Result<Self> {
+        Ok(Self {
+            root: settings.root.canonicalize()?,
+            max_age_secs: settings.cache_max_age_secs,
+        })
+    }
+
+    /// Resolve a request path to a file inside the root. Rocket already
+    /// refuses `..` segments, this also catches symlinks leading out
+    fn resolve(&self, file: &Path) -> Option<PathBuf> {
+        match self.root.join(file).canonicalize() {
+            Ok(ref full) if full.starts_with(&self.root) && full.is_file() => Some(full.clone()),
+            _ => None,
+        }
+    }
+
+    fn open(&self, file: &Path) -> Option<Cached> {
+        let path = match self.resolve(file) {
+            Some(p) => p,
+            None => return None,
+        };
+
+        // Pages should pick up new deployments right away, assets may be
+        // cached for a while
+        let is_page = path.extension().map_or(false, |e| e == "html");
+        let cache_control = if is_page {
+            "no-cache".to_string()
+        } else {
+            format!("public, max-age={}", self.max_age_secs)
+        };
+
+        NamedFile::open(path).ok().map(|file| {
+            Cached {
+                file: file,
+                cache_control: cache_control,
+            }
+        })
+    }
+}
+
+/// A static file, with a `Cache-Control` header. The `Content-Type`
+/// comes from `NamedFile`, based on the file extension
+pub struct Cached {
+    file: NamedFile,
+    cache_control: String,
+}
+
+impl<'r> Responder<'r> for Cached {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = self.file.respond_to(request)?;
+        response.set_header(Header::new("Cache-Control", self.cache_control));
+        Ok(response)
+    }
+}
+
+#[get("/")]
+pub fn index(root: State<FrontendRoot>) -> Option<Cached> {
+    root.open(Path::new("index.html"))
+}
+
+#[get("/<file..>", rank = 10)]
+pub fn files(file: PathBuf, root: State<FrontendRoot>) -> Option<Cached> {
+    root.open(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_inside_root() {
+        let root = FrontendRoot::new(&FrontendSettings::default()).unwrap();
+
+        assert!(root.resolve(Path::new("index.html")).is_some());
+        assert!(root.resolve(Path::new("static/css/custom.css")).is_some());
+
+        // Directories and paths out of the root are not served
+        assert!(root.resolve(Path::new("static")).is_none());
+        assert!(root.resolve(Path::new("../backend/Cargo.toml")).is_none());
+        assert!(root.resolve(Path::new("/etc/passwd")).is_none());
+    }
+}
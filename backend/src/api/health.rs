@@ -0,0 +1,173 @@
+use std::fs;
+
+use chrono::{Datelike, Duration};
+use mvdb::Mvdb;
+use rocket::State;
+use rocket::http::{ContentType, Status};
+use rocket::response::content::Content;
+use rocket::response::status::Custom;
+use rocket_contrib::{Json, Value};
+
+use datetime_utils::ProveWhenTime;
+use hooks;
+use metrics::METRICS;
+use pub_key_storage::KeyDB;
+use settings::Settings;
+use status::{age, read_keystore, STATUS};
+use timesource::TIME_SOURCES;
+
+/// No key should have been generated before the project existed, and
+/// a clock reading earlier than this is certainly wrong
+const EARLIEST_SANE_YEAR: i32 = 2017;
+
+pub struct Report {
+    pub ready: bool,
+    pub body: Value,
+}
+
+fn opt_str(time: &Option<ProveWhenTime>) -> Value {
+    match *time {
+        Some(ref t) => json!(t.as_str()),
+        None => Value::Null,
+    }
+}
+
+fn opt_age(time: &Option<ProveWhenTime>) -> Value {
+    match *time {
+        Some(ref t) => json!(age(t).num_seconds()),
+        None => Value::Null,
+    }
+}
+
+pub fn report(keydb: &Mvdb<KeyDB>, settings: &Settings) -> Report {
+    let now = ProveWhenTime::now();
+
+    let keys = read_keystore(keydb, |db| {
+        (
+            db.current_key_time().clone(),
+            db.latest_key_time().cloned(),
+            db.key_count(),
+            db.high_water().cloned(),
+            db.clock_regression(),
+        )
+    });
+
+    let (key_time, latest_key, key_count, high_water, regression) = match keys {
+        Ok(k) => k,
+        Err(e) => {
+            return Report {
+                ready: false,
+                body: json!({
+                    "status": "failure",
+                    "message": format!("Keystore access failed: {}", e),
+                }),
+            }
+        }
+    };
+
+    // The current key is stale if it should already have been rotated
+    let key_fresh = key_time >= now.floored();
+
+    // The clock is sane if it isn't behind any key we've issued
+    let clock_sane = now.inner().year() >= EARLIEST_SANE_YEAR &&
+        latest_key.as_ref().map_or(true, |k| *k <= now);
+
+    let rotator_alive = STATUS.rotator_alive(settings.rotation.heartbeat_timeout());
+    let last_write = STATUS.last_keystore_write();
+    let keystore_bytes = fs::metadata(&settings.keystore).map(|m| m.len()).ok();
+
+    let paused = STATUS.signing_paused();
+
+    let measurement = TIME_SOURCES.latest();
+    let clock_offset = TIME_SOURCES.excess_offset();
+
+    let ready = key_fresh && clock_sane && regression.is_none() && clock_offset.is_none() &&
+        rotator_alive && last_write.is_some() && !paused;
+
+    let hook_summary = hooks::summary();
+
+    let body = json!({
+        "status": if ready { "ok" } else { "degraded" },
+        "current_key": {
+            "time": key_time.as_str(),
+            "age_secs": age(&key_time).num_seconds(),
+            "expires": key_time.next_period().as_str(),
+            "fresh": key_fresh,
+        },
+        "signing": {
+            "paused": paused,
+        },
+        "rotator": {
+            "alive": rotator_alive,
+            "last_heartbeat": opt_str(&STATUS.last_rotator_heartbeat()),
+            "heartbeat_age_secs": opt_age(&STATUS.last_rotator_heartbeat()),
+        },
+        "keystore": {
+            "last_write": opt_str(&last_write),
+            "last_write_age_secs": opt_age(&last_write),
+            "size_bytes": keystore_bytes,
+            "keys": key_count,
+        },
+        "clock": {
+            "now": now.as_str(),
+            "sane": clock_sane,
+            "ahead_of_latest_key_secs": latest_key
+                .map(|k| now.inner().signed_duration_since(*k.inner()))
+                .unwrap_or(Duration::zero())
+                .num_seconds(),
+            "high_water": opt_str(&high_water),
+            "regressed": regression.is_some(),
+            "behind_high_water_ms": regression.map(|d| d.num_milliseconds()),
+        },
+        "time_sources": {
+            "configured": settings.time_sources.servers.len(),
+            "responded": measurement.as_ref().map(|m| m.responses),
+            "measured_at": opt_str(&measurement.as_ref().map(|m| m.measured_at.clone())),
+            "offset_ms": measurement.as_ref().map(|m| m.offset.num_milliseconds()),
+            "uncertainty_ms": measurement.as_ref().map(|m| m.uncertainty.num_milliseconds()),
+            "accuracy_ms": TIME_SOURCES.accuracy().map(|a| a.num_milliseconds()),
+            "offset_exceeded": clock_offset.is_some(),
+        },
+        "hooks": {
+            "configured": settings.hooks.targets.len(),
+            "pending": hook_summary.as_ref().map(|h| h.pending),
+            "failed": hook_summary.as_ref().map(|h| h.failed),
+            "last_error": hook_summary.and_then(|h| h.last_error),
+        },
+    });
+
+    Report {
+        ready: ready,
+        body: body,
+    }
+}
+
+/// Detailed service state. Always `200`, for dashboards and on-call
+#[get("/health")]
+pub fn health(keydb: State<Mvdb<KeyDB>>, settings: State<Settings>) -> Json<Value> {
+    Json(report(&keydb, &settings).body)
+}
+
+/// Same report as `/health`, but `503` unless the service can sign,
+/// for load balancers
+#[get("/ready")]
+pub fn ready(keydb: State<Mvdb<KeyDB>>, settings: State<Settings>) -> Custom<Json<Value>> {
+    let report = report(&keydb, &settings);
+
+    let status = if report.ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    Custom(status, Json(report.body))
+}
+
+/// Counters and histograms in the Prometheus text format
+#[get("/metrics")]
+pub fn metrics() -> Content<String> {
+    Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        METRICS.render(),
+    )
+}
@@ -0,0 +1,28 @@
+use rocket::Data;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::request::Request;
+use rocket::response::Response;
+
+use status::STATUS;
+
+/// Counts the requests being handled, so shutdown can wait for them
+/// before the final keystore write. A response is counted as finished
+/// once its headers are ready, so open event streams don't hold it up
+pub struct InFlight;
+
+impl Fairing for InFlight {
+    fn info(&self) -> Info {
+        Info {
+            name: "In-flight requests",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, _: &mut Request, _: &Data) {
+        STATUS.request_started();
+    }
+
+    fn on_response(&self, _: &Request, _: &mut Response) {
+        STATUS.request_finished();
+    }
+}
@@ -1,22 +1,69 @@
 use rocket;
+use rocket::Route;
 use mvdb::Mvdb;
 use pub_key_storage::KeyDB;
+use settings::Settings;
 
+pub mod admin;
+pub mod cors;
 pub mod endpoints;
+pub mod events;
+pub mod frontend;
+pub mod health;
+pub mod in_flight;
+pub mod negotiate;
+pub mod openapi;
+pub mod quota;
 pub mod types;
+pub mod upload;
 
-pub fn setup_rocket(keydb: Mvdb<KeyDB>) -> rocket::Rocket {
-    rocket::ignite()
-        .mount(
-            "/api/v1/",
-            routes![
-                endpoints::hello,
-
-                endpoints::sign,
-                endpoints::key_time,
-                endpoints::verify,
-                endpoints::key_time_range,
-            ],
-        )
+pub const MOUNT_POINT: &str = "/api/v1";
+
+/// All routes served under `MOUNT_POINT`
+pub fn routes() -> Vec<Route> {
+    routes![
+        endpoints::hello,
+
+        endpoints::sign,
+        endpoints::sign_token,
+        upload::sign_upload,
+        endpoints::time,
+        endpoints::key_current,
+        events::key_events,
+        events::key_events_since,
+        endpoints::key_time,
+        endpoints::verify,
+        endpoints::verify_mode,
+        endpoints::key_time_range,
+        endpoints::jwks,
+        endpoints::jwks_range,
+
+        openapi::openapi,
+    ]
+}
+
+pub fn setup_rocket(keydb: Mvdb<KeyDB>, settings: Settings) -> rocket::Rocket {
+    let quotas = quota::Quotas::new(settings.auth.clone());
+
+    let mut rocket = rocket::ignite();
+
+    if settings.frontend.enabled {
+        let root = frontend::FrontendRoot::new(&settings.frontend)
+            .expect("Failed to open frontend root");
+
+        rocket = rocket
+            .mount("/", routes![frontend::index, frontend::files])
+            .manage(root);
+    }
+
+    rocket
+        .mount("/", routes![health::health, health::ready, health::metrics])
+        .mount(MOUNT_POINT, routes())
+        .catch(errors![quota::unauthorized, quota::too_many_requests])
+        .attach(in_flight::InFlight)
+        .attach(quota::QuotaHeaders)
+        .attach(cors::Cors::new(settings.cors.clone()))
         .manage(keydb)
+        .manage(quotas)
+        .manage(settings)
 }
@@ -0,0 +1,196 @@
+use std::io::{Cursor, Read};
+use std::ops::Deref;
+
+use rocket::data::{self, Data, FromData};
+use rocket::http::{Accept, ContentType, MediaType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::Outcome;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_cbor;
+use serde_json;
+use rmp_serde;
+
+use errors::*;
+
+/// Largest request body accepted by `Body`
+const BODY_LIMIT: u64 = 1024 * 1024;
+
+/// Wire encodings supported by the API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl Encoding {
+    pub fn from_media_type(media_type: &MediaType) -> Option<Self> {
+        let (top, sub) = (media_type.top(), media_type.sub());
+
+        if top == "*" && sub == "*" {
+            return Some(Encoding::Json);
+        }
+
+        if top != "application" {
+            return None;
+        }
+
+        if sub == "json" || sub == "*" {
+            Some(Encoding::Json)
+        } else if sub == "cbor" {
+            Some(Encoding::Cbor)
+        } else if sub == "msgpack" || sub == "x-msgpack" {
+            Some(Encoding::MsgPack)
+        } else {
+            None
+        }
+    }
+
+    /// Pick the encoding the client prefers most. No `Accept` header
+    /// means JSON, an `Accept` header with nothing we support means `None`
+    pub fn from_accept(accept: Option<&Accept>) -> Option<Self> {
+        let accept = match accept {
+            Some(a) => a,
+            None => return Some(Encoding::Json),
+        };
+
+        let mut candidates = accept.iter().collect::<Vec<_>>();
+
+        // Stable sort, so equally weighted types keep the client's order
+        candidates.sort_by(|a, b| {
+            b.weight_or(1.0)
+                .partial_cmp(&a.weight_or(1.0))
+                .unwrap_or(::std::cmp::Ordering::Equal)
+        });
+
+        candidates
+            .iter()
+            .filter(|qmt| qmt.weight_or(1.0) > 0.0)
+            .filter_map(|qmt| Self::from_media_type(qmt.media_type()))
+            .next()
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match *self {
+            Encoding::Json => ContentType::JSON,
+            Encoding::Cbor => ContentType::new("application", "cbor"),
+            Encoding::MsgPack => ContentType::new("application", "msgpack"),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match *self {
+            Encoding::Json => serde_json::to_vec(value).chain_err(|| "Failed to encode JSON"),
+            Encoding::Cbor => serde_cbor::to_vec(value).chain_err(|| "Failed to encode CBOR"),
+            Encoding::MsgPack => {
+                rmp_serde::to_vec_named(value).chain_err(|| "Failed to encode MessagePack")
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match *self {
+            Encoding::Json => serde_json::from_slice(bytes).chain_err(|| "Failed to decode JSON"),
+            Encoding::Cbor => serde_cbor::from_slice(bytes).chain_err(|| "Failed to decode CBOR"),
+            Encoding::MsgPack => {
+                rmp_serde::from_slice(bytes).chain_err(|| "Failed to decode MessagePack")
+            }
+        }
+    }
+}
+
+/// A response body, encoded as JSON, CBOR or MessagePack depending
+/// on the request's `Accept` header
+pub struct Negotiated<T>(pub T);
+
+impl<'r, T: Serialize> Responder<'r> for Negotiated<T> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let encoding = Encoding::from_accept(request.accept()).ok_or(Status::NotAcceptable)?;
+
+        let body = encoding
+            .encode(&self.0)
+            .map_err(|_| Status::InternalServerError)?;
+
+        Ok(
+            Response::build()
+                .header(encoding.content_type())
+                .sized_body(Cursor::new(body))
+                .finalize(),
+        )
+    }
+}
+
+/// A request body, decoded from JSON, CBOR or MessagePack depending
+/// on the request's `Content-Type` header. A missing `Content-Type`
+/// is treated as JSON
+pub struct Body<T>(pub T);
+
+impl<T> Deref for Body<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromData for Body<T> {
+    type Error = Error;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let encoding = match request.content_type() {
+            Some(ct) => match Encoding::from_media_type(ct) {
+                Some(e) => e,
+                None => return Outcome::Forward(data),
+            },
+            None => Encoding::Json,
+        };
+
+        let mut raw = Vec::new();
+        if let Err(e) = data.open().take(BODY_LIMIT).read_to_end(&mut raw) {
+            return Outcome::Failure((
+                Status::BadRequest,
+                Error::with_chain(e, "Failed to read request body"),
+            ));
+        }
+
+        match encoding.decode(&raw) {
+            Ok(value) => Outcome::Success(Body(value)),
+            Err(e) => Outcome::Failure((Status::BadRequest, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datetime_utils::ProveWhenTime;
+    use key_types::{SignResponse, SingleKeySet};
+
+    #[test]
+    fn round_trip_all_encodings() {
+        let signed = SingleKeySet::new()
+            .sign(ProveWhenTime::now(), "This is a test of negotiation", None)
+            .unwrap();
+
+        for encoding in &[Encoding::Json, Encoding::Cbor, Encoding::MsgPack] {
+            let raw = encoding.encode(&signed).unwrap();
+            let decoded: SignResponse = encoding.decode(&raw).unwrap();
+            assert!(decoded == signed, "{:?} did not round trip", encoding);
+        }
+    }
+
+    #[test]
+    fn media_types() {
+        let cbor = MediaType::new("application", "cbor");
+        let msgpack = MediaType::new("application", "x-msgpack");
+        let html = MediaType::new("text", "html");
+
+        assert_eq!(Encoding::from_media_type(&MediaType::JSON), Some(Encoding::Json));
+        assert_eq!(Encoding::from_media_type(&MediaType::Any), Some(Encoding::Json));
+        assert_eq!(Encoding::from_media_type(&cbor), Some(Encoding::Cbor));
+        assert_eq!(Encoding::from_media_type(&msgpack), Some(Encoding::MsgPack));
+        assert_eq!(Encoding::from_media_type(&html), None);
+    }
+}
@@ -0,0 +1,1017 @@
+use rocket::http::Method;
+use rocket::Route;
+use rocket_contrib::{Json, Value};
+
+use api;
+use api::upload;
+use api::types::*;
+use events::KeyEvent;
+use key_types::{KeyProof, KeyStatement, SingleKeySet, KEY_ALGORITHM};
+use settings::DEFAULT_SERVER_ID;
+use datetime_utils::ProveWhenTime;
+
+/// Types that appear in request or response bodies of the API
+pub trait Schema {
+    /// Name of the schema under `#/components/schemas/`
+    fn name() -> &'static str;
+
+    /// JSON Schema (OpenAPI flavor) describing the serialized form
+    fn schema() -> Value;
+
+    /// A representative value, used to check `schema()` against serde
+    fn example() -> Self;
+
+    /// Values that between them have every optional property and enum
+    /// variant, for the same check
+    fn examples() -> Vec<Self>
+    where
+        Self: Sized,
+    {
+        vec![Self::example()]
+    }
+}
+
+/// Documentation for a single route. The method and path must match
+/// a route mounted by `api::setup_rocket`, using Rocket's `<param>` syntax
+struct Operation {
+    method: Method,
+    path: &'static str,
+    describe: fn() -> Value,
+}
+
+const OPERATIONS: &[Operation] = &[
+    Operation {
+        method: Method::Get,
+        path: "/hello",
+        describe: op_hello,
+    },
+    Operation {
+        method: Method::Post,
+        path: "/sign",
+        describe: op_sign,
+    },
+    Operation {
+        method: Method::Post,
+        path: "/sign/<format>",
+        describe: op_sign_token,
+    },
+    Operation {
+        method: Method::Post,
+        path: "/sign/upload/<name>",
+        describe: op_sign_upload,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/time/<nonce>",
+        describe: op_time,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/key/current",
+        describe: op_key_current,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/key/events",
+        describe: op_key_events,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/key/events/<since>",
+        describe: op_key_events_since,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/key/time/<time>",
+        describe: op_key_time,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/key/time/<start>/<end>",
+        describe: op_key_time_range,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/jwks.json",
+        describe: op_jwks,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/jwks/<start>/<end>",
+        describe: op_jwks_range,
+    },
+    Operation {
+        method: Method::Post,
+        path: "/verify",
+        describe: op_verify,
+    },
+    Operation {
+        method: Method::Post,
+        path: "/verify/<mode>",
+        describe: op_verify_mode,
+    },
+    Operation {
+        method: Method::Get,
+        path: "/openapi.json",
+        describe: op_openapi,
+    },
+];
+
+#[get("/openapi.json", format = "application/json")]
+pub fn openapi() -> Json<Value> {
+    Json(spec())
+}
+
+/// Render the OpenAPI 3 document for every route in `api::routes()`
+pub fn spec() -> Value {
+    let mut doc = json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": "provewhen.io",
+            "description": "Signed timestamps for arbitrary messages, using hourly rotated Ed25519 keys",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [
+            { "url": api::MOUNT_POINT },
+        ],
+        "paths": {},
+        "components": {
+            "schemas": {},
+            "securitySchemes": {
+                "ApiKey": { "type": "apiKey", "in": "header", "name": "X-Api-Key" },
+                "Bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    });
+
+    for route in api::routes() {
+        let path = to_openapi_path(route.uri.path());
+        let method = route.method.as_str().to_lowercase();
+
+        doc["paths"][&path][&method] = match find_operation(&route) {
+            Some(op) => (op.describe)(),
+            None => json!({ "summary": "undocumented" }),
+        };
+    }
+
+    add_schema::<SignRequest>(&mut doc);
+    add_schema::<SignResponse>(&mut doc);
+    add_schema::<TimeResponse>(&mut doc);
+    add_schema::<KeyResponse>(&mut doc);
+    add_schema::<KeyStatement>(&mut doc);
+    add_schema::<KeyRangeResponse>(&mut doc);
+    add_schema::<CurrentKeyResponse>(&mut doc);
+    add_schema::<KeyEvent>(&mut doc);
+    add_schema::<DigestReceipt>(&mut doc);
+    add_schema::<Jwk>(&mut doc);
+    add_schema::<JwkSet>(&mut doc);
+
+    doc
+}
+
+fn add_schema<T: Schema>(doc: &mut Value) {
+    doc["components"]["schemas"][T::name()] = T::schema();
+}
+
+fn find_operation(route: &Route) -> Option<&'static Operation> {
+    OPERATIONS
+        .iter()
+        .find(|op| op.method == route.method && op.path == route.uri.path())
+}
+
+/// Convert Rocket's `<param>` segments to OpenAPI's `{param}`
+fn to_openapi_path(path: &str) -> String {
+    path.replace('<', "{").replace('>', "}")
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Media types accepted and produced by endpoints using `negotiate`
+const NEGOTIATED_TYPES: &[&str] = &["application/json", "application/cbor", "application/msgpack"];
+
+fn negotiated_content(schema: Value) -> Value {
+    let mut content = json!({});
+    for media_type in NEGOTIATED_TYPES {
+        content[*media_type] = json!({ "schema": schema.clone() });
+    }
+    content
+}
+
+fn negotiated_body<T: Schema>() -> Value {
+    json!({
+        "required": true,
+        "content": negotiated_content(schema_ref(T::name())),
+    })
+}
+
+fn negotiated_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": negotiated_content(schema),
+    })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "application/json": { "schema": schema },
+        },
+    })
+}
+
+fn error_response() -> Value {
+    json_response(
+        "The request failed",
+        json!({
+            "type": "object",
+            "properties": {
+                "status": { "type": "string", "enum": ["failure"] },
+                "message": { "type": "string" },
+            },
+        }),
+    )
+}
+
+fn time_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": format!(
+            "{}. RFC 3339 with any offset, Unix seconds (10 digits) or milliseconds \
+             (13 digits), or an ISO 8601 calendar (`2018-03-01`), week (`2018-W09-4`) \
+             or ordinal (`2018-060`) date, optionally with `T`, a time and an offset. \
+             A date alone means midnight UTC",
+            description
+        ),
+        "schema": { "type": "string" },
+    })
+}
+
+/// Mark an operation as guarded by `quota::ApiClient`. An API key is
+/// optional unless the server is configured to require one
+fn rate_limited(mut op: Value) -> Value {
+    op["security"] = json!([{}, { "ApiKey": [] }, { "Bearer": [] }]);
+    op["responses"]["401"] = error_response();
+    op["responses"]["429"] = error_response();
+    op
+}
+
+fn op_hello() -> Value {
+    json!({
+        "summary": "Liveness check",
+        "responses": {
+            "200": json_response("A greeting", json!({ "type": "string" })),
+        },
+    })
+}
+
+fn op_sign() -> Value {
+    rate_limited(json!({
+        "summary": "Sign a message with the current hourly key",
+        "requestBody": negotiated_body::<SignRequest>(),
+        "responses": {
+            "200": negotiated_response("A signed receipt", schema_ref(SignResponse::name())),
+            "400": error_response(),
+        },
+    }))
+}
+
+fn op_sign_token() -> Value {
+    rate_limited(json!({
+        "summary": "Sign a message, returning the receipt as a JWS or COSE_Sign1 token",
+        "description": "The token's `kid` is the RFC 7638 thumbprint of the signing key. \
+                        JWS tokens use the JOSE compact serialization with `alg` EdDSA, \
+                        COSE tokens are a tagged COSE_Sign1 structure with `alg` -8.",
+        "parameters": [
+            {
+                "name": "format",
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string", "enum": ["jws", "cose"] },
+            },
+        ],
+        "requestBody": negotiated_body::<SignRequest>(),
+        "responses": {
+            "200": {
+                "description": "A signed receipt token",
+                "content": {
+                    "application/jose": { "schema": { "type": "string" } },
+                    "application/cose": { "schema": { "type": "string", "format": "binary" } },
+                },
+            },
+            "400": error_response(),
+        },
+    }))
+}
+
+fn op_sign_upload() -> Value {
+    rate_limited(json!({
+        "summary": "Stream a file to the server, and sign its SHA-256 digest",
+        "description": "The body is hashed as it arrives and never stored. The receipt's \
+                        message is `sha-256=<digest>;size=<size>;name=<name>`, so it can be \
+                        checked with `/verify`.",
+        "parameters": [
+            {
+                "name": "name",
+                "in": "path",
+                "required": true,
+                "description": "URL encoded file name, at most 255 bytes",
+                "schema": { "type": "string" },
+            },
+        ],
+        "requestBody": {
+            "required": true,
+            "content": {
+                "application/octet-stream": { "schema": { "type": "string", "format": "binary" } },
+            },
+        },
+        "responses": {
+            "200": negotiated_response("A digest receipt", schema_ref(DigestReceipt::name())),
+            "400": error_response(),
+            "413": error_response(),
+        },
+    }))
+}
+
+fn op_time() -> Value {
+    rate_limited(json!({
+        "summary": "Get a signed reading of the server clock",
+        "description": "The signature covers `time;<time>;accuracy_ms=<accuracy_ms>;nonce=<nonce>`, \
+                        made with the current hourly key. Clients compare `time` with their \
+                        own clock to detect skew.",
+        "parameters": [
+            {
+                "name": "nonce",
+                "in": "path",
+                "required": true,
+                "description": "Client chosen nonce, 1 to 128 letters, digits or `-_+=.`",
+                "schema": { "type": "string", "pattern": "^[A-Za-z0-9_+=.-]{1,128}$" },
+            },
+        ],
+        "responses": {
+            "200": negotiated_response("The signed time", schema_ref(TimeResponse::name())),
+            "400": error_response(),
+        },
+    }))
+}
+
+fn op_key_current() -> Value {
+    json!({
+        "summary": "Get the key currently used for signing, and when it expires",
+        "description": "Shortly before `expires`, the key for the next period is included \
+                        as `next`, so verification caches can be warmed before rotation.",
+        "responses": {
+            "200": negotiated_response("The current key", schema_ref(CurrentKeyResponse::name())),
+            "400": error_response(),
+        },
+    })
+}
+
+fn event_stream_response() -> Value {
+    json!({
+        "description": "A Server-Sent Events stream. Each event's `id` is the key time it \
+                        refers to, `event` is `rotation`, `gap` or `revocation`, and \
+                        `data` is a JSON `KeyEvent`",
+        "content": {
+            "text/event-stream": { "schema": schema_ref(KeyEvent::name()) },
+        },
+    })
+}
+
+fn op_key_events() -> Value {
+    json!({
+        "summary": "Stream key rotation, gap and revocation events as they happen",
+        "parameters": [
+            {
+                "name": "Last-Event-ID",
+                "in": "header",
+                "required": false,
+                "description": "Replay events after this key time before streaming live events",
+                "schema": { "type": "string", "format": "date-time" },
+            },
+        ],
+        "responses": {
+            "200": event_stream_response(),
+            "503": { "description": "Too many open streams" },
+        },
+    })
+}
+
+fn op_key_events_since() -> Value {
+    json!({
+        "summary": "Replay key events after a key time, then stream live ones",
+        "parameters": [
+            time_param("since", "Replay events after this time"),
+        ],
+        "responses": {
+            "200": event_stream_response(),
+            "400": { "description": "`since` is not a recognised time" },
+            "503": { "description": "Too many open streams" },
+        },
+    })
+}
+
+fn op_key_time() -> Value {
+    json!({
+        "summary": "Get the public key that was active at a given time",
+        "parameters": [
+            time_param("time", "Must not be in the future"),
+        ],
+        "responses": {
+            "200": negotiated_response("The key active at `time`", schema_ref(KeyResponse::name())),
+            "400": error_response(),
+        },
+    })
+}
+
+fn op_key_time_range() -> Value {
+    json!({
+        "summary": "Get the public keys generated between two times (at most 50)",
+        "parameters": [
+            time_param("start", "Start of the range"),
+            time_param("end", "End of the range"),
+        ],
+        "responses": {
+            "200": negotiated_response("Keys in the range", schema_ref(KeyRangeResponse::name())),
+            "400": error_response(),
+        },
+    })
+}
+
+fn jwks_response() -> Value {
+    json!({
+        "description": "An RFC 7517 JWK Set of OKP/Ed25519 keys, with `nbf`/`exp` validity. \
+                        Revoked keys are left out",
+        "content": {
+            "application/jwk-set+json": { "schema": schema_ref(JwkSet::name()) },
+        },
+    })
+}
+
+fn op_jwks() -> Value {
+    json!({
+        "summary": "Get the keys in use during the last 24 hours as a JWK Set",
+        "responses": {
+            "200": jwks_response(),
+            "400": error_response(),
+        },
+    })
+}
+
+fn op_jwks_range() -> Value {
+    json!({
+        "summary": "Get the keys in use between two times as a JWK Set (at most 50, most recent first kept)",
+        "parameters": [
+            time_param("start", "Start of the window"),
+            time_param("end", "End of the window"),
+        ],
+        "responses": {
+            "200": jwks_response(),
+            "400": error_response(),
+        },
+    })
+}
+
+fn verify_response() -> Value {
+    negotiated_response(
+        "The receipt is valid",
+        json!({
+            "type": "object",
+            "properties": {
+                "result": { "type": "string", "enum": ["ok"] },
+                "mode": { "type": "string", "enum": ["strict", "lenient"] },
+                "timestamp": time_prop("The receipt's timestamp"),
+                "accuracy_ms": {
+                    "type": "integer",
+                    "nullable": true,
+                    "description": "The receipt's signed accuracy, null for receipts without one",
+                },
+                "not_before": {
+                    "type": "string",
+                    "format": "date-time",
+                    "nullable": true,
+                    "description": "`timestamp` minus the accuracy: the message was signed no earlier than this",
+                },
+                "not_after": {
+                    "type": "string",
+                    "format": "date-time",
+                    "nullable": true,
+                    "description": "`timestamp` plus the accuracy: the message was signed no later than this",
+                },
+            },
+        }),
+    )
+}
+
+fn op_verify() -> Value {
+    json!({
+        "summary": "Verify a previously issued receipt",
+        "description": "Receipts with `version` 2 or later are verified strictly, older ones \
+                        leniently. See `/verify/{mode}`.",
+        "requestBody": negotiated_body::<SignResponse>(),
+        "responses": {
+            "200": verify_response(),
+            "400": error_response(),
+        },
+    })
+}
+
+fn op_verify_mode() -> Value {
+    json!({
+        "summary": "Verify a previously issued receipt in a given mode",
+        "description": "`lenient` checks the signature and that the key was in use at \
+                        `timestamp`. `strict` also checks that `key_time` matches that key, \
+                        that `timestamp` is within the key's validity period and that the \
+                        nonce is `provewhen.io:` followed by 256 bits of base64.",
+        "parameters": [
+            {
+                "name": "mode",
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string", "enum": ["strict", "lenient"] },
+            },
+        ],
+        "requestBody": negotiated_body::<SignResponse>(),
+        "responses": {
+            "200": verify_response(),
+            "400": error_response(),
+        },
+    })
+}
+
+fn op_openapi() -> Value {
+    json!({
+        "summary": "This document",
+        "responses": {
+            "200": json_response("OpenAPI 3 description of the API", json!({ "type": "object" })),
+        },
+    })
+}
+
+// --------
+
+fn string_prop(description: &str) -> Value {
+    json!({ "type": "string", "description": description })
+}
+
+fn time_prop(description: &str) -> Value {
+    json!({ "type": "string", "format": "date-time", "description": description })
+}
+
+impl Schema for SignRequest {
+    fn name() -> &'static str {
+        "SignRequest"
+    }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["message"],
+            "properties": {
+                "message": string_prop("UTF-8 message to be signed"),
+            },
+        })
+    }
+
+    fn example() -> Self {
+        SignRequest { message: "Hello!".into() }
+    }
+}
+
+impl Schema for SignResponse {
+    fn name() -> &'static str {
+        "SignResponse"
+    }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["timestamp", "key_time", "public_key", "message", "signature", "nonce"],
+            "properties": {
+                "version": {
+                    "type": "integer",
+                    "description": "Receipt format version, 1 if absent. Covered by the signature from \
+                                    version 2 on",
+                },
+                "timestamp": time_prop("Time the message was signed"),
+                "key_time": time_prop("Time the signing key was generated"),
+                "public_key": string_prop("Base64 encoded Ed25519 public key"),
+                "message": string_prop("UTF-8 message that was signed"),
+                "signature": string_prop("Base64 encoded Ed25519 signature. Version 1 receipts sign \
+                                          `timestamp;message;nonce`, later versions sign \
+                                          `receipt;v<version>` followed by `;<length>:<field>` for \
+                                          timestamp, message, nonce and accuracy_ms, lengths in bytes"),
+                "nonce": string_prop("`provewhen.io:` followed by 256 bits of base64 encoded random data"),
+                "accuracy_ms": {
+                    "type": "integer",
+                    "description": "Bound on the error of `timestamp` in milliseconds, measured against the \
+                                    time sources or else configured. Absent from receipts issued before \
+                                    accuracies were stated",
+                },
+            },
+        })
+    }
+
+    fn example() -> Self {
+        SingleKeySet::new()
+            .sign(ProveWhenTime::now(), "Hello!", Some(1000))
+            .unwrap()
+    }
+}
+
+impl Schema for TimeResponse {
+    fn name() -> &'static str {
+        "TimeResponse"
+    }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["time", "accuracy_ms", "client_nonce", "key_time", "public_key", "signature"],
+            "properties": {
+                "time": time_prop("Server time when the request was handled"),
+                "accuracy_ms": { "type": "integer", "description": "Bound on the error of `time`, in milliseconds" },
+                "client_nonce": string_prop("The nonce from the request"),
+                "key_time": time_prop("Time the signing key was generated"),
+                "public_key": string_prop("Base64 encoded Ed25519 public key"),
+                "signature": string_prop("Base64 encoded Ed25519 signature over `time;<time>;accuracy_ms=<accuracy_ms>;nonce=<client_nonce>`"),
+            },
+        })
+    }
+
+    fn example() -> Self {
+        SingleKeySet::new()
+            .sign_time(ProveWhenTime::now(), "c2tldy1jaGVjaw", 1000)
+            .unwrap()
+    }
+}
+
+impl Schema for TimedPublicKey {
+    fn name() -> &'static str {
+        "TimedPublicKey"
+    }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["time", "public_key", "proof"],
+            "properties": {
+                "time": time_prop("Time the key was generated"),
+                "public_key": string_prop("Base64 encoded Ed25519 public key"),
+                "proof": {
+                    "description": "A `KeyStatement`. Keys logged by older servers are proven \
+                                    by a `SignResponse` for an arbitrary message instead",
+                    "oneOf": [
+                        schema_ref(KeyStatement::name()),
+                        schema_ref(SignResponse::name()),
+                    ],
+                },
+                "revoked_at": time_prop("Time an operator revoked the key, absent unless revoked. \
+                                         Receipts signed with a revoked key no longer verify"),
+            },
+        })
+    }
+
+    fn example() -> Self {
+        TimedPublicKey::new(&SingleKeySet::new(), None, DEFAULT_SERVER_ID)
+    }
+
+    fn examples() -> Vec<Self> {
+        let key = Self::example();
+        let revoked = key.marked_revoked(&ProveWhenTime::now());
+        vec![key, revoked]
+    }
+}
+
+impl Schema for KeyStatement {
+    fn name() -> &'static str {
+        "KeyStatement"
+    }
+
+    fn schema() -> Value {
+        let mut previous = string_prop("RFC 7638 thumbprint of the key before this one");
+        previous["nullable"] = json!(true);
+
+        json!({
+            "type": "object",
+            "required": ["key_time", "valid_until", "algorithm", "previous_key_hash", "server_id", "signature"],
+            "properties": {
+                "key_time": time_prop("Start of the key's period, equal to the key's `time`"),
+                "valid_until": time_prop("Scheduled end of the key's period. A forced rotation ends it early"),
+                "algorithm": { "type": "string", "enum": [KEY_ALGORITHM] },
+                "previous_key_hash": previous,
+                "server_id": string_prop("Identifies the server that generated the key"),
+                "signature": string_prop(
+                    "Base64 encoded Ed25519 signature by the key itself over \
+                     `provewhen.io key statement;public_key=<public_key>;key_time=<key_time>;\
+                     valid_until=<valid_until>;algorithm=<algorithm>;\
+                     previous_key_hash=<previous_key_hash, or empty>;server_id=<server_id>`",
+                ),
+            },
+        })
+    }
+
+    fn example() -> Self {
+        let previous = TimedPublicKey::example();
+        let key = TimedPublicKey::new(&SingleKeySet::new(), Some(&previous), DEFAULT_SERVER_ID);
+
+        match *key.proof() {
+            KeyProof::Statement(ref statement) => statement.clone(),
+            KeyProof::Legacy(_) => unreachable!("new keys are proven by a statement"),
+        }
+    }
+}
+
+impl Schema for KeyRangeResponse {
+    fn name() -> &'static str {
+        "KeyRangeResponse"
+    }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["keys"],
+            "properties": {
+                "keys": {
+                    "type": "array",
+                    "items": schema_ref(TimedPublicKey::name()),
+                },
+            },
+        })
+    }
+
+    fn example() -> Self {
+        KeyRangeResponse { keys: vec![TimedPublicKey::example()] }
+    }
+}
+
+impl Schema for CurrentKeyResponse {
+    fn name() -> &'static str {
+        "CurrentKeyResponse"
+    }
+
+    fn schema() -> Value {
+        let mut next = schema_ref(TimedPublicKey::name());
+        next["nullable"] = json!(true);
+
+        json!({
+            "type": "object",
+            "required": ["key", "expires", "next"],
+            "properties": {
+                "key": schema_ref(TimedPublicKey::name()),
+                "expires": time_prop("Time `key` will be rotated out"),
+                "next": next,
+            },
+        })
+    }
+
+    fn example() -> Self {
+        let key = TimedPublicKey::example();
+        CurrentKeyResponse {
+            expires: key.time().next_period(),
+            key: key,
+            next: Some(TimedPublicKey::example()),
+        }
+    }
+}
+
+impl Schema for KeyEvent {
+    fn name() -> &'static str {
+        "KeyEvent"
+    }
+
+    fn schema() -> Value {
+        // Properties of every variant, `type` says which are present
+        json!({
+            "type": "object",
+            "required": ["type"],
+            "properties": {
+                "type": { "type": "string", "enum": ["rotation", "gap", "revocation"] },
+                "key": schema_ref(TimedPublicKey::name()),
+                "from": time_prop("gap: last key time before the gap"),
+                "to": time_prop("gap: key time after the gap"),
+                "keys": { "type": "integer", "description": "gap: number of back-filled keys" },
+                "key_time": time_prop("revocation: time of the revoked key"),
+                "public_key": string_prop("revocation: base64 encoded revoked public key"),
+                "reason": string_prop("revocation: why the key was revoked"),
+            },
+        })
+    }
+
+    fn example() -> Self {
+        KeyEvent::Rotation { key: TimedPublicKey::example() }
+    }
+
+    fn examples() -> Vec<Self> {
+        let key = TimedPublicKey::example();
+
+        vec![
+            Self::example(),
+            KeyEvent::Gap {
+                from: key.time().clone(),
+                to: key.time().next_period().next_period(),
+                keys: 1,
+            },
+            KeyEvent::Revocation {
+                key_time: key.time().clone(),
+                public_key: key.public_key().to_string(),
+                reason: "suspected leak".into(),
+            },
+        ]
+    }
+}
+
+impl Schema for DigestReceipt {
+    fn name() -> &'static str {
+        "DigestReceipt"
+    }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["file_name", "size", "digest", "receipt"],
+            "properties": {
+                "file_name": string_prop("Name the file was uploaded under"),
+                "size": { "type": "integer", "description": "File size in bytes" },
+                "digest": string_prop("Base64 encoded SHA-256 digest of the file"),
+                "receipt": schema_ref(SignResponse::name()),
+            },
+        })
+    }
+
+    fn example() -> Self {
+        let digest = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".to_string();
+        DigestReceipt {
+            file_name: "empty.bin".into(),
+            size: 0,
+            receipt: SingleKeySet::new()
+                .sign(ProveWhenTime::now(), &upload::digest_message(&digest, 0, "empty.bin"), Some(1000))
+                .unwrap(),
+            digest: digest,
+        }
+    }
+}
+
+impl Schema for Jwk {
+    fn name() -> &'static str {
+        "Jwk"
+    }
+
+    fn schema() -> Value {
+        let int_prop = |description: &str| json!({ "type": "integer", "description": description });
+
+        json!({
+            "type": "object",
+            "required": ["kty", "crv", "x", "kid", "use", "alg", "nbf", "exp"],
+            "properties": {
+                "kty": { "type": "string", "enum": ["OKP"] },
+                "crv": { "type": "string", "enum": ["Ed25519"] },
+                "x": string_prop("Base64url encoded Ed25519 public key"),
+                "kid": string_prop("RFC 7638 thumbprint of the key"),
+                "use": { "type": "string", "enum": ["sig"] },
+                "alg": { "type": "string", "enum": ["EdDSA"] },
+                "nbf": int_prop("Unix time the key was generated"),
+                "exp": int_prop("Unix time the key was rotated out"),
+            },
+        })
+    }
+
+    fn example() -> Self {
+        let key = TimedPublicKey::example();
+        let valid_until = key.time().next_period();
+        Jwk::from_timed_key(&key, &valid_until).unwrap()
+    }
+}
+
+impl Schema for JwkSet {
+    fn name() -> &'static str {
+        "JwkSet"
+    }
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["keys"],
+            "properties": {
+                "keys": {
+                    "type": "array",
+                    "items": schema_ref(Jwk::name()),
+                },
+            },
+        })
+    }
+
+    fn example() -> Self {
+        JwkSet { keys: vec![Jwk::example()] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde::Serialize;
+    use serde_json;
+
+    use super::*;
+
+    fn keys_of(value: &Value) -> BTreeSet<String> {
+        value
+            .as_object()
+            .expect("not an object")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn check_schema<T: Schema + Serialize>() {
+        let examples = T::examples()
+            .into_iter()
+            .map(|e| serde_json::to_value(e).unwrap())
+            .collect::<Vec<_>>();
+        let schema = T::schema();
+
+        let serialized = examples.iter().flat_map(keys_of).collect::<BTreeSet<_>>();
+        assert_eq!(
+            keys_of(&schema["properties"]),
+            serialized,
+            "schema for {} does not match its serialized form",
+            T::name()
+        );
+
+        for example in &examples {
+            for field in schema["required"].as_array().unwrap() {
+                let field = field.as_str().unwrap();
+                assert!(
+                    !example[field].is_null(),
+                    "{}.{} is required but missing",
+                    T::name(),
+                    field
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_route_is_documented() {
+        for route in api::routes() {
+            assert!(
+                find_operation(&route).is_some(),
+                "route {} {} is missing from OPERATIONS",
+                route.method,
+                route.uri.path()
+            );
+        }
+    }
+
+    #[test]
+    fn every_operation_is_routed() {
+        let routes = api::routes();
+        for op in OPERATIONS {
+            assert!(
+                routes
+                    .iter()
+                    .any(|r| r.method == op.method && r.uri.path() == op.path),
+                "documented operation {} {} is not routed",
+                op.method,
+                op.path
+            );
+        }
+    }
+
+    #[test]
+    fn schemas_match_types() {
+        check_schema::<SignRequest>();
+        check_schema::<SignResponse>();
+        check_schema::<TimeResponse>();
+        check_schema::<KeyResponse>();
+        check_schema::<KeyStatement>();
+        check_schema::<KeyRangeResponse>();
+        check_schema::<CurrentKeyResponse>();
+        check_schema::<KeyEvent>();
+        check_schema::<DigestReceipt>();
+        check_schema::<Jwk>();
+        check_schema::<JwkSet>();
+    }
+
+    #[test]
+    fn schema_refs_resolve() {
+        let doc = spec();
+        let rendered = doc.to_string();
+        for name in keys_of(&doc["components"]["schemas"]) {
+            assert!(rendered.contains(&format!("#/components/schemas/{}", name)));
+        }
+        for chunk in rendered.split("#/components/schemas/").skip(1) {
+            let name = chunk.split('"').next().unwrap();
+            assert!(
+                !doc["components"]["schemas"][name].is_null(),
+                "dangling schema reference to {}",
+                name
+            );
+        }
+    }
+}
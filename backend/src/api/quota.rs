@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use chrono::{Date, Duration, Utc};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::Response;
+use rocket::{Outcome, State};
+use rocket_contrib::{Json, Value};
+
+use api_keys::ApiKeyRegistry;
+use metrics::METRICS;
+use settings::AuthSettings;
+
+/// Usage entries kept before idle ones are pruned
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Clients not seen for this long are forgotten first when pruning
+const IDLE_SECS: u64 = 60 * 60;
+
+/// Who a request is accounted to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Identity {
+    Key(String), // API key id
+    Ip(IpAddr),
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub per_minute: u32,
+    pub daily: u64,
+}
+
+/// A client's standing after a request, used for the quota headers
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub limits: Limits,
+    pub remaining_minute: u32,
+    pub remaining_daily: u64,
+    pub reset: i64, // unix time the daily quota resets
+}
+
+struct Usage {
+    limits: Limits, // as of the client's latest request
+    tokens: f64,
+    last_refill: Instant,
+    day: Date<Utc>,
+    used_today: u64,
+}
+
+impl Usage {
+    fn new(limits: &Limits) -> Self {
+        Self {
+            limits: *limits,
+            tokens: limits.per_minute as f64,
+            last_refill: Instant::now(),
+            day: Utc::today(),
+            used_today: 0,
+        }
+    }
+
+    /// Top up the per-minute bucket and roll over the daily quota
+    fn refill(&mut self) {
+        let elapsed = self.last_refill.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        let per_minute = self.limits.per_minute as f64;
+
+        self.tokens = (self.tokens + secs * per_minute / 60.0).min(per_minute);
+        self.last_refill = Instant::now();
+
+        let today = Utc::today();
+        if today != self.day {
+            self.day = today;
+            self.used_today = 0;
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            limits: self.limits,
+            remaining_minute: self.tokens.floor() as u32,
+            remaining_daily: self.limits.daily.saturating_sub(self.used_today),
+            reset: (self.day + Duration::days(1)).and_hms(0, 0, 0).timestamp(),
+        }
+    }
+}
+
+/// Per-client token buckets and daily counters. These live in memory
+/// only, a restart resets everyone's quota
+pub struct RateLimiter {
+    usage: Mutex<HashMap<Identity, Usage>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self { usage: Mutex::new(HashMap::new()) }
+    }
+
+    /// Account for one request. `Err` means the client is over a limit
+    pub fn take(&self, id: &Identity, limits: &Limits) -> Result<Snapshot, Snapshot> {
+        let mut usage = self.usage.lock().unwrap();
+
+        if usage.len() >= MAX_TRACKED_CLIENTS && !usage.contains_key(id) {
+            prune(&mut usage);
+        }
+
+        let entry = usage.entry(id.clone()).or_insert_with(|| Usage::new(limits));
+        entry.limits = *limits;
+        entry.refill();
+
+        if entry.tokens < 1.0 || entry.used_today >= limits.daily {
+            return Err(entry.snapshot());
+        }
+
+        entry.tokens -= 1.0;
+        entry.used_today += 1;
+
+        Ok(entry.snapshot())
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.usage.lock().unwrap().len()
+    }
+}
+
+/// Forget clients that have been idle for a while, then the longest idle
+/// ones until there is room for a tenth more. Each prune makes room for
+/// many new clients, so the scan doesn't run on every request
+fn prune(usage: &mut HashMap<Identity, Usage>) {
+    usage.retain(|_, entry| entry.last_refill.elapsed().as_secs() < IDLE_SECS);
+
+    let target = MAX_TRACKED_CLIENTS - MAX_TRACKED_CLIENTS / 10;
+    if usage.len() > target {
+        let mut by_age = usage
+            .iter()
+            .map(|(id, entry)| (entry.last_refill, id.clone()))
+            .collect::<Vec<_>>();
+        by_age.sort_by_key(|&(last, _)| last);
+
+        let excess = usage.len() - target;
+        for &(_, ref id) in by_age.iter().take(excess) {
+            usage.remove(id);
+        }
+    }
+}
+
+/// Managed state for API key authentication and rate limiting
+pub struct Quotas {
+    settings: AuthSettings,
+    registry: Mutex<ApiKeyRegistry>,
+    limiter: RateLimiter,
+}
+
+impl Quotas {
+    pub fn new(settings: AuthSettings) -> Self {
+        let registry = ApiKeyRegistry::new(&settings.keys_file);
+
+        Self {
+            settings: settings,
+            registry: Mutex::new(registry),
+            limiter: RateLimiter::new(),
+        }
+    }
+
+    /// Work out who is making a request, and which limits apply to them
+    fn identify(&self, request: &Request) -> Result<(Identity, Limits), Status> {
+        let presented = request.headers().get_one("X-Api-Key").or_else(|| {
+            request
+                .headers()
+                .get_one("Authorization")
+                .and_then(|h| if h.starts_with("Bearer ") { Some(&h[7..]) } else { None })
+        });
+
+        if let Some(secret) = presented {
+            let key = self.registry
+                .lock()
+                .unwrap()
+                .lookup(secret.trim())
+                .ok_or(Status::Unauthorized)?;
+
+            let limits = Limits {
+                per_minute: key.per_minute.unwrap_or(self.settings.per_key_per_minute),
+                daily: key.daily_quota.unwrap_or(self.settings.per_key_daily),
+            };
+
+            return Ok((Identity::Key(key.id), limits));
+        }
+
+        if self.settings.required {
+            return Err(Status::Unauthorized);
+        }
+
+        let limits = Limits {
+            per_minute: self.settings.per_ip_per_minute,
+            daily: self.settings.per_ip_daily,
+        };
+
+        match request.remote() {
+            Some(addr) => Ok((Identity::Ip(addr.ip()), limits)),
+            None => Ok((Identity::Unknown, limits)),
+        }
+    }
+}
+
+/// Request guard for rate limited endpoints. Rejects requests with an
+/// unknown API key (or none, if keys are required) and clients that
+/// are over their limits
+pub struct ApiClient {
+    pub identity: Identity,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiClient {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let quotas = match request.guard::<State<Quotas>>() {
+            Outcome::Success(q) => q,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+
+        let (identity, limits) = match quotas.identify(request) {
+            Ok(found) => found,
+            Err(status) => {
+                METRICS.sign_failures.inc("unauthorized");
+                return Outcome::Failure((status, ()));
+            }
+        };
+
+        let taken = quotas.limiter.take(&identity, &limits);
+
+        // For `QuotaHeaders`, which would otherwise identify the client again
+        let snapshot = match taken {
+            Ok(snap) | Err(snap) => snap,
+        };
+        request.local_cache(|| RequestQuota(Some(snapshot)));
+
+        match taken {
+            Ok(_) => Outcome::Success(ApiClient { identity: identity }),
+            Err(_) => {
+                METRICS.sign_failures.inc("rate_limited");
+                Outcome::Failure((Status::TooManyRequests, ()))
+            }
+        }
+    }
+}
+
+/// The client's standing, cached on the request by the `ApiClient` guard
+struct RequestQuota(Option<Snapshot>);
+
+/// Adds `X-RateLimit-*` and `X-Quota-*` headers to responses of rate
+/// limited endpoints
+pub struct QuotaHeaders;
+
+impl Fairing for QuotaHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Quota headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let RequestQuota(Some(snap)) = *request.local_cache(|| RequestQuota(None)) {
+            let headers = [
+                ("X-RateLimit-Limit", snap.limits.per_minute.to_string()),
+                ("X-RateLimit-Remaining", snap.remaining_minute.to_string()),
+                ("X-Quota-Limit", snap.limits.daily.to_string()),
+                ("X-Quota-Remaining", snap.remaining_daily.to_string()),
+                ("X-Quota-Reset", snap.reset.to_string()),
+            ];
+
+            for &(name, ref value) in headers.iter() {
+                response.set_header(Header::new(name, value.clone()));
+            }
+        }
+    }
+}
+
+#[error(401)]
+pub fn unauthorized() -> Json<Value> {
+    Json(json!({
+        "status": "failure",
+        "message": "A valid API key is required",
+    }))
+}
+
+#[error(429)]
+pub fn too_many_requests() -> Json<Value> {
+    Json(json!({
+        "status": "failure",
+        "message": "Rate limit or daily quota exceeded",
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_and_quota() {
+        let limiter = RateLimiter::new();
+        let id = Identity::Unknown;
+
+        let per_minute = Limits { per_minute: 3, daily: 100 };
+        for n in 0..3 {
+            assert_eq!(limiter.take(&id, &per_minute).unwrap().remaining_minute, 2 - n);
+        }
+        assert!(limiter.take(&id, &per_minute).is_err());
+
+        let daily = Limits { per_minute: 100, daily: 2 };
+        let other = Identity::Key("abcd".into());
+        assert!(limiter.take(&other, &daily).is_ok());
+        assert!(limiter.take(&other, &daily).is_ok());
+        assert_eq!(limiter.take(&other, &daily).unwrap_err().remaining_daily, 0);
+
+        // The anonymous client's limits don't touch the key's bucket
+        assert!(limiter.take(&id, &per_minute).is_err());
+        assert_eq!(limiter.take(&other, &daily).unwrap_err().limits.per_minute, 100);
+    }
+
+    #[test]
+    fn busy_clients_are_pruned_when_full() {
+        let limiter = RateLimiter::new();
+        let limits = Limits { per_minute: 10, daily: 100 };
+
+        for n in 0..MAX_TRACKED_CLIENTS {
+            limiter.take(&Identity::Key(n.to_string()), &limits).unwrap();
+        }
+        assert_eq!(limiter.tracked(), MAX_TRACKED_CLIENTS);
+
+        // Everyone was seen today, the longest idle make room anyway
+        limiter.take(&Identity::Unknown, &limits).unwrap();
+        assert_eq!(limiter.tracked(), MAX_TRACKED_CLIENTS - MAX_TRACKED_CLIENTS / 10 + 1);
+    }
+}
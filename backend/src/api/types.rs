@@ -1,4 +1,9 @@
-pub use key_types::{SignResponse, TimedPublicKey};
+pub use key_types::{SignResponse, TimeResponse, TimedPublicKey};
+
+use base64;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
 
 #[derive(Serialize, Deserialize)]
 pub struct SignRequest {
@@ -12,3 +17,56 @@ pub type KeyResponse = TimedPublicKey;
 pub struct KeyRangeResponse {
     pub keys: Vec<KeyResponse>,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct CurrentKeyResponse {
+    pub key: KeyResponse,
+    pub expires: ProveWhenTime,    // rfc3339 timestamp, when `key` is rotated out
+    pub next: Option<KeyResponse>, // announced shortly before `expires`
+}
+
+/// Receipt for a file uploaded to `/sign/upload/<name>`. The receipt's
+/// message is `sha-256=<digest>;size=<size>;name=<file_name>`
+#[derive(Serialize, Deserialize)]
+pub struct DigestReceipt {
+    pub file_name: String,
+    pub size: u64,        // bytes
+    pub digest: String,   // base64 encoded SHA-256 of the file
+    pub receipt: SignResponse,
+}
+
+/// An Ed25519 public key as an RFC 7517 / RFC 8037 JSON Web Key
+#[derive(Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String, // always "OKP"
+    pub crv: String, // always "Ed25519"
+    pub x: String,   // base64url encoded public key
+    pub kid: String, // RFC 7638 thumbprint
+    #[serde(rename = "use")]
+    pub use_: String, // always "sig"
+    pub alg: String, // always "EdDSA"
+    pub nbf: i64,    // seconds since the unix epoch, key generation time
+    pub exp: i64,    // seconds since the unix epoch, key rotation time
+}
+
+impl Jwk {
+    pub fn from_timed_key(key: &TimedPublicKey, valid_until: &ProveWhenTime) -> Result<Self> {
+        let pk = base64::decode(key.public_key()).chain_err(|| "failed to decode")?;
+
+        Ok(Jwk {
+            kty: "OKP".into(),
+            crv: "Ed25519".into(),
+            x: base64::encode_config(&pk, base64::URL_SAFE_NO_PAD),
+            kid: key.kid()?,
+            use_: "sig".into(),
+            alg: "EdDSA".into(),
+            nbf: key.time().inner().timestamp(),
+            exp: valid_until.inner().timestamp(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
@@ -0,0 +1,124 @@
+use std::io::Read;
+
+use base64;
+use mvdb::Mvdb;
+use ring::digest;
+use rocket::Data;
+use rocket::State;
+use rocket::http::RawStr;
+
+use api::negotiate::Negotiated;
+use api::quota::ApiClient;
+use api::types::DigestReceipt;
+use errors as echain;
+use errors::{ErrorKind, ResultExt};
+use pub_key_storage::KeyDB;
+use metrics::METRICS;
+use settings::Settings;
+use status::{write_keystore, STATUS};
+
+/// Size of each read from the upload stream
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest accepted file name, in bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// The message signed for an uploaded file. The name goes last, so it
+/// may contain any character without making the message ambiguous
+pub fn digest_message(digest: &str, size: u64, file_name: &str) -> String {
+    format!("sha-256={};size={};name={}", digest, size, file_name)
+}
+
+/// Hash a stream without holding more than one chunk in memory,
+/// returning the base64 SHA-256 digest and the number of bytes read
+fn hash_stream<R: Read>(mut stream: R, max_bytes: u64) -> echain::Result<(String, u64)> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut size: u64 = 0;
+
+    loop {
+        let n = stream
+            .read(&mut buf)
+            .chain_err(|| "Failed to read upload")?;
+
+        if n == 0 {
+            break;
+        }
+
+        size += n as u64;
+        if size > max_bytes {
+            bail!(ErrorKind::PayloadTooLarge(max_bytes));
+        }
+
+        ctx.update(&buf[..n]);
+    }
+
+    Ok((base64::encode(ctx.finish().as_ref()), size))
+}
+
+#[post("/sign/upload/<name>", data = "<file>")]
+pub fn sign_upload(
+    _client: ApiClient,
+    name: &RawStr,
+    file: Data,
+    settings: State<Settings>,
+    keydb: State<Mvdb<KeyDB>>,
+) -> Result<Negotiated<DigestReceipt>, echain::Error> {
+    METRICS.observe_sign("sign_upload", sign_file(name, file, &settings, &keydb))
+        .map(Negotiated)
+}
+
+fn sign_file(
+    name: &RawStr,
+    file: Data,
+    settings: &Settings,
+    keydb: &Mvdb<KeyDB>,
+) -> echain::Result<DigestReceipt> {
+    STATUS.check_signing()?;
+
+    let file_name = name.url_decode().chain_err(|| "File name is not valid UTF-8")?;
+    if file_name.is_empty() || file_name.len() > MAX_NAME_LEN {
+        bail!("File name must be between 1 and {} bytes", MAX_NAME_LEN);
+    }
+
+    // Hash outside of the keystore lock, this may take a while
+    let (digest, size) = hash_stream(file.open(), settings.upload.max_bytes)?;
+    let message = digest_message(&digest, size, &file_name);
+
+    let sgd = write_keystore(keydb, |db| db.sign(&message))?;
+
+    Ok(DigestReceipt {
+        file_name: file_name,
+        size: size,
+        digest: digest,
+        receipt: sgd?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn hash_in_chunks() {
+        let data = vec![0x5Au8; CHUNK_SIZE * 3 + 17];
+        let (digest, size) = hash_stream(Cursor::new(&data), data.len() as u64).unwrap();
+
+        let expected = base64::encode(digest::digest(&digest::SHA256, &data).as_ref());
+        assert_eq!(digest, expected);
+        assert_eq!(size, data.len() as u64);
+    }
+
+    #[test]
+    fn hash_over_limit() {
+        let data = vec![0u8; 1000];
+        let err = hash_stream(Cursor::new(&data), 999).unwrap_err();
+
+        match *err.kind() {
+            ErrorKind::PayloadTooLarge(999) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use base64;
+use mvdb::helpers::just_load;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest;
+use ring::rand::SecureRandom;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use key_types::RANDOM;
+
+/// Prefix of every generated secret, so they're easy to spot in logs
+const SECRET_PREFIX: &str = "pw_";
+
+/// How often the server checks the key file for changes, in seconds
+const REFRESH_INTERVAL_SECS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub id: String,         // short public identifier
+    pub name: String,       // who the key was issued to
+    pub key_hash: String,   // base64 encoded SHA-256 of the secret
+    pub created: ProveWhenTime,
+
+    // Overrides for the defaults in `AuthSettings`
+    #[serde(default)]
+    pub per_minute: Option<u32>,
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+/// The API key file. Only hashes of the secrets are stored
+#[derive(Serialize, Deserialize, Default)]
+pub struct ApiKeyDB {
+    keys: Vec<ApiKey>,
+}
+
+fn hash_secret(secret: &str) -> String {
+    base64::encode(digest::digest(&digest::SHA256, secret.as_bytes()).as_ref())
+}
+
+impl ApiKeyDB {
+    pub fn keys(&self) -> &[ApiKey] {
+        &self.keys
+    }
+
+    /// Create a new key, returning the secret. The secret is not stored,
+    /// so this is the only time it is available
+    pub fn generate(
+        &mut self,
+        name: &str,
+        per_minute: Option<u32>,
+        daily_quota: Option<u64>,
+    ) -> Result<(String, ApiKey)> {
+        let mut raw = [0u8; 32];
+        RANDOM
+            .deref()
+            .fill(&mut raw[..])
+            .chain_err(|| "Failed to generate key")?;
+
+        let secret = format!(
+            "{}{}",
+            SECRET_PREFIX,
+            base64::encode_config(&raw[..], base64::URL_SAFE_NO_PAD)
+        );
+        let key_hash = hash_secret(&secret);
+
+        let key = ApiKey {
+            id: key_hash.chars().filter(|c| c.is_alphanumeric()).take(8).collect(),
+            name: name.into(),
+            key_hash: key_hash,
+            created: ProveWhenTime::now(),
+            per_minute: per_minute,
+            daily_quota: daily_quota,
+            revoked: false,
+        };
+
+        self.keys.push(key.clone());
+
+        Ok((secret, key))
+    }
+
+    pub fn revoke(&mut self, id: &str) -> Result<()> {
+        match self.keys.iter_mut().find(|k| k.id == id) {
+            Some(key) => {
+                key.revoked = true;
+                Ok(())
+            }
+            None => bail!("No API key with id {}", id),
+        }
+    }
+
+    /// Find the active key matching a secret presented by a client
+    pub fn lookup(&self, secret: &str) -> Option<&ApiKey> {
+        let presented = hash_secret(secret);
+
+        self.keys.iter().filter(|k| !k.revoked).find(|k| {
+            verify_slices_are_equal(k.key_hash.as_bytes(), presented.as_bytes()).is_ok()
+        })
+    }
+}
+
+/// A read-only view of the API key file, reloaded when the file changes.
+/// The file itself is managed with the `apikey` subcommand
+pub struct ApiKeyRegistry {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_check: Option<Instant>,
+    db: ApiKeyDB,
+}
+
+impl ApiKeyRegistry {
+    pub fn new(path: &Path) -> Self {
+        let mut new = Self {
+            path: path.to_path_buf(),
+            modified: None,
+            last_check: None,
+            db: ApiKeyDB::default(),
+        };
+
+        new.refresh();
+
+        new
+    }
+
+    /// Reload the key file if it has changed since the last check. A
+    /// missing or unreadable file accepts no keys, so removing it revokes
+    /// them all rather than keeping the last set forever
+    pub fn refresh(&mut self) {
+        match self.last_check {
+            Some(t) if t.elapsed() < Duration::from_secs(REFRESH_INTERVAL_SECS) => return,
+            _ => self.last_check = Some(Instant::now()),
+        }
+
+        let modified = match fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(m) => Some(m),
+            Err(_) => {
+                if self.modified.take().is_some() {
+                    println!("API key file {} is gone, no keys are accepted", self.path.display());
+                }
+                self.db = ApiKeyDB::default();
+                return;
+            }
+        };
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+
+        match just_load::<ApiKeyDB>(&self.path) {
+            Ok(db) => {
+                println!("Loaded {} API keys", db.keys.len());
+                self.db = db;
+            }
+            Err(e) => {
+                println!("ALERT: failed to load API keys, none are accepted until the file is fixed: {}", e);
+                self.db = ApiKeyDB::default();
+            }
+        }
+    }
+
+    pub fn lookup(&mut self, secret: &str) -> Option<ApiKey> {
+        self.refresh();
+        self.db.lookup(secret).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+
+    use chrono::Utc;
+    use serde_json;
+
+    use super::*;
+
+    #[test]
+    fn generate_lookup_revoke() {
+        let mut db = ApiKeyDB::default();
+
+        let (secret, key) = db.generate("tester", Some(5), None).unwrap();
+        assert!(secret.starts_with(SECRET_PREFIX));
+        assert!(!key.key_hash.contains(&secret));
+
+        assert_eq!(db.lookup(&secret).unwrap().id, key.id);
+        assert!(db.lookup("pw_not-a-real-key").is_none());
+
+        db.revoke(&key.id).unwrap();
+        assert!(db.lookup(&secret).is_none());
+    }
+
+    #[test]
+    fn removed_key_file_accepts_nothing() {
+        let path = env::temp_dir().join(format!(
+            "provewhen-api-keys-{}.json",
+            Utc::now().timestamp_subsec_nanos()
+        ));
+
+        let mut db = ApiKeyDB::default();
+        let (secret, _) = db.generate("tester", None, None).unwrap();
+        File::create(&path)
+            .and_then(|mut f| f.write_all(serde_json::to_string(&db).unwrap().as_bytes()))
+            .unwrap();
+
+        let mut registry = ApiKeyRegistry::new(&path);
+        assert!(registry.lookup(&secret).is_some());
+
+        fs::remove_file(&path).unwrap();
+        registry.last_check = None;
+        assert!(registry.lookup(&secret).is_none());
+
+        // Nor does a file that can't be parsed
+        File::create(&path).and_then(|mut f| f.write_all(b"{")).unwrap();
+        registry.last_check = None;
+        assert!(registry.lookup(&secret).is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+
+use mvdb::Mvdb;
+use mvdb::helpers::just_load;
+use serde_json;
+
+use api::admin;
+use api::types::KeyRangeResponse;
+use api_keys::ApiKeyDB;
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use key_types::{self, MasterSeed};
+use pub_key_storage::KeyDB;
+use settings::{AdminSettings, Settings};
+
+const USAGE: &str = "\
+usage:
+    provewhen                       run the server
+    provewhen apikey add <name> [--per-minute <n>] [--daily <n>]
+    provewhen apikey list
+    provewhen apikey revoke <id>
+    provewhen admin status
+    provewhen admin rotate [--revoke <reason>]
+    provewhen admin pause
+    provewhen admin resume
+    provewhen seed generate
+    provewhen seed check [--keys <file>]
+    provewhen seed derive <start> <end> [--generations <n>]";
+
+/// Handle any subcommand given on the command line. Returns `false`
+/// if there was none, and the server should start
+pub fn run(args: &[String], settings: &Settings) -> Result<bool> {
+    let args = args.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+
+    match args.first() {
+        None => Ok(false),
+        Some(&"apikey") => {
+            apikey(&args[1..], &settings.auth.keys_file)?;
+            Ok(true)
+        }
+        Some(&"admin") => {
+            admin_command(&args[1..], &settings.admin)?;
+            Ok(true)
+        }
+        Some(&"seed") => {
+            seed(&args[1..], settings)?;
+            Ok(true)
+        }
+        Some(&"help") | Some(&"--help") | Some(&"-h") => {
+            println!("{}", USAGE);
+            Ok(true)
+        }
+        Some(other) => bail!("unknown command {:?}\n{}", other, USAGE),
+    }
+}
+
+/// Find `--flag <value>` in `args` and parse the value
+fn flag<T: ::std::str::FromStr>(args: &[&str], name: &str) -> Result<Option<T>> {
+    match args.iter().position(|a| *a == name) {
+        None => Ok(None),
+        Some(n) => match args.get(n + 1).and_then(|v| v.parse().ok()) {
+            Some(v) => Ok(Some(v)),
+            None => bail!("{} is missing its value, or it is invalid", name),
+        },
+    }
+}
+
+fn apikey(args: &[&str], path: &Path) -> Result<()> {
+    let db: Mvdb<ApiKeyDB> = Mvdb::from_file_or_default_pretty(path)?;
+
+    match args.first() {
+        Some(&"add") => {
+            let name = match args.get(1) {
+                Some(n) if !n.starts_with("--") => *n,
+                _ => bail!("apikey add needs a name\n{}", USAGE),
+            };
+            let per_minute = flag(args, "--per-minute")?;
+            let daily = flag(args, "--daily")?;
+
+            let (secret, key) = db.access_mut(|db| db.generate(name, per_minute, daily))??;
+
+            println!("Created key {} for {}", key.id, key.name);
+            println!("Secret (shown once): {}", secret);
+        }
+        Some(&"list") => {
+            db.access(|db| {
+                for key in db.keys() {
+                    println!(
+                        "{}\t{}\t{}\tper-minute: {}\tdaily: {}{}",
+                        key.id,
+                        key.name,
+                        key.created.as_str(),
+                        key.per_minute.map_or("default".into(), |n| n.to_string()),
+                        key.daily_quota.map_or("default".into(), |n| n.to_string()),
+                        if key.revoked { "\tREVOKED" } else { "" },
+                    );
+                }
+            })?;
+        }
+        Some(&"revoke") => {
+            let id = match args.get(1) {
+                Some(id) => *id,
+                None => bail!("apikey revoke needs a key id\n{}", USAGE),
+            };
+
+            db.access_mut(|db| db.revoke(id))??;
+            println!("Revoked key {}", id);
+        }
+        _ => bail!("unknown apikey command\n{}", USAGE),
+    }
+
+    Ok(())
+}
+
+/// Create the sealed master seed, or check it regenerates the logged keys
+fn seed(args: &[&str], settings: &Settings) -> Result<()> {
+    let seed_settings = &settings.key_seed;
+
+    match args.first() {
+        Some(&"generate") => {
+            let passphrase = key_types::passphrase(seed_settings)?;
+            let sealed = MasterSeed::generate()?.seal(&passphrase, seed_settings.iterations)?;
+            sealed.save(&seed_settings.seed_file)?;
+
+            println!("Sealed a new master seed in {}", seed_settings.seed_file.display());
+            println!("Back it up, and the passphrase separately, before enabling key_seed");
+        }
+        Some(&"check") => {
+            let seed = key_types::unseal(seed_settings)?;
+
+            // A saved `/keys/<start>/<end>` response, or the keystore
+            let checked = match flag::<String>(args, "--keys")? {
+                Some(path) => {
+                    let range: KeyRangeResponse = just_load(Path::new(&path))
+                        .chain_err(|| format!("Failed to load keys from {}", path))?;
+                    let derived = seed.check(&range.keys);
+                    range.keys.into_iter().zip(derived).collect::<Vec<_>>()
+                }
+                None => {
+                    let keydb: KeyDB = just_load(&settings.keystore)
+                        .chain_err(|| format!("Failed to load {}", settings.keystore.display()))?;
+                    keydb.check_master_seed(&seed)
+                }
+            };
+
+            // Keys from before the seed was used are random, every key
+            // after the first derived one must be derived too
+            let mut seen_derived = false;
+            let mut mismatches = 0;
+            for (key, derived) in checked {
+                let note = match (derived, seen_derived) {
+                    (true, _) => "derived",
+                    (false, false) => "random, from before the seed was used",
+                    (false, true) => {
+                        mismatches += 1;
+                        "MISMATCH"
+                    }
+                };
+                seen_derived |= derived;
+
+                println!("{}\t{}\t{}", key.time().as_str(), key.public_key(), note);
+            }
+
+            if mismatches > 0 {
+                bail!("{} keys were not regenerated by the master seed", mismatches);
+            }
+            if !seen_derived {
+                println!("No key was derived from this seed yet");
+            }
+        }
+        Some(&"derive") => {
+            let (start, end) = match (args.get(1), args.get(2)) {
+                (Some(start), Some(end)) => (
+                    ProveWhenTime::parse_lenient(start)?,
+                    ProveWhenTime::parse_lenient(end)?,
+                ),
+                _ => bail!("seed derive needs a start and an end time\n{}", USAGE),
+            };
+            let generations = flag(args, "--generations")?.unwrap_or(1);
+            if generations == 0 {
+                bail!("--generations must be at least 1");
+            }
+
+            let seed = key_types::unseal(seed_settings)?;
+            for (n, key) in seed.derive_range(&start, &end, generations).iter().enumerate() {
+                println!(
+                    "{}\t{}\t{}",
+                    key.time_generated.as_str(),
+                    n as u32 % generations,
+                    key.pub_key_base64
+                );
+            }
+        }
+        _ => bail!("unknown seed command\n{}", USAGE),
+    }
+
+    Ok(())
+}
+
+/// Run an admin command against the local server's admin API
+fn admin_command(args: &[&str], settings: &AdminSettings) -> Result<()> {
+    let token = admin::read_token(&settings.token_file)?;
+
+    let (method, path, body) = match args.first() {
+        Some(&"status") => ("GET", "/status", None),
+        Some(&"rotate") => {
+            let reason: Option<String> = flag(args, "--revoke")?;
+            let req = admin::RotateRequest { revoke: reason };
+            let body = serde_json::to_string(&req).chain_err(|| "Failed to encode request")?;
+            ("POST", "/rotate", Some(body))
+        }
+        Some(&"pause") => ("POST", "/pause", None),
+        Some(&"resume") => ("POST", "/resume", None),
+        _ => bail!("unknown admin command\n{}", USAGE),
+    };
+
+    let path = format!("{}{}", admin::MOUNT_POINT, path);
+    let (status, response) = http_request(settings.port, &token, method, &path, body)?;
+
+    // Pretty print JSON responses, anything else is shown as is
+    let pretty = serde_json::from_str::<serde_json::Value>(&response)
+        .and_then(|v| serde_json::to_string_pretty(&v))
+        .unwrap_or(response);
+    println!("{}", pretty);
+
+    if status != 200 {
+        bail!("admin request failed with status {}", status);
+    }
+
+    Ok(())
+}
+
+/// Just enough HTTP/1.1 to talk to the admin API on localhost. Returns
+/// the status code and the body
+fn http_request(
+    port: u16,
+    token: &str,
+    method: &str,
+    path: &str,
+    body: Option<String>,
+) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))
+        .chain_err(|| format!("Failed to connect to the admin API on port {}, is it enabled?", port))?;
+
+    let body = body.unwrap_or_default();
+    let request = format!(
+        "{} {} HTTP/1.1\r\n\
+         Host: 127.0.0.1:{}\r\n\
+         Authorization: Bearer {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        method,
+        path,
+        port,
+        token,
+        body.len(),
+        body
+    );
+
+    stream
+        .write_all(request.as_bytes())
+        .chain_err(|| "Failed to send admin request")?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .chain_err(|| "Failed to read admin response")?;
+
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::from("Malformed admin response"))?;
+
+    let body = match response.find("\r\n\r\n") {
+        Some(n) => response[n + 4..].to_string(),
+        None => String::new(),
+    };
+
+    Ok((status, body))
+}
@@ -0,0 +1,82 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+
+use datetime_utils::ProveWhenTime;
+use timesource::TIME_SOURCES;
+
+/// Source of the current time for key rotation, lookups and signing
+pub trait Clock: Send + Sync {
+    fn now(&self) -> ProveWhenTime;
+
+    /// Bound on the error of `now`, if it has been measured
+    fn accuracy(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The host's clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> ProveWhenTime {
+        ProveWhenTime::now()
+    }
+
+    fn accuracy(&self) -> Option<Duration> {
+        TIME_SOURCES.accuracy()
+    }
+}
+
+pub fn system() -> Arc<Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when told to, for tests
+pub struct ManualClock {
+    time: Mutex<DateTime<Utc>>,
+    accuracy: Mutex<Option<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        ManualClock {
+            time: Mutex::new(start),
+            accuracy: Mutex::new(None),
+        }
+    }
+
+    /// Start at an RFC 3339 time
+    pub fn at(start: &str) -> Self {
+        Self::new(*ProveWhenTime::from_str(start).expect("invalid start time").inner())
+    }
+
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.time.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut time = self.time.lock().unwrap();
+        *time = *time + by;
+    }
+
+    pub fn set_accuracy(&self, accuracy: Option<Duration>) {
+        *self.accuracy.lock().unwrap() = accuracy;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> ProveWhenTime {
+        ProveWhenTime::from_inner(*self.time.lock().unwrap())
+    }
+
+    fn accuracy(&self) -> Option<Duration> {
+        *self.accuracy.lock().unwrap()
+    }
+}
+
+/// An RFC 3339 time, for tests
+#[cfg(test)]
+pub fn at(time: &str) -> ProveWhenTime {
+    ProveWhenTime::from_str(time).expect("invalid time")
+}
@@ -56,11 +56,17 @@ impl<'de> Deserialize<'de> for ProveWhenTime {
     }
 }
 
+/// Times in URLs may be written in any of the forms `parse_lenient`
+/// accepts. Routes take a `Result` to report why a time was rejected
 impl<'r> FromParam<'r> for ProveWhenTime {
-    type Error = &'r RawStr;
+    type Error = Error;
 
-    fn from_param(param: &'r RawStr) -> ::std::result::Result<Self, Self::Error> {
-        ProveWhenTime::from_str(&param.html_escape()).map_err(|_| param)
+    fn from_param(param: &'r RawStr) -> Result<Self> {
+        let decoded = param
+            .url_decode()
+            .chain_err(|| format!("Time {:?} is not valid UTF-8 once decoded", param.as_str()))?;
+
+        ProveWhenTime::parse_lenient(&decoded)
     }
 }
 
@@ -106,6 +112,74 @@ impl ProveWhenTime {
         })
     }
 
+    /// Parse any of the forms accepted in URLs, all normalised to UTC:
+    ///
+    /// * Unix time, as 10 digits of seconds or 13 of milliseconds
+    /// * RFC 3339, with any offset. A space in place of the `+` of the
+    ///   offset is taken as a `+` lost to form encoding
+    /// * ISO 8601 calendar (`2018-03-01`), week (`2018-W09-4`) and
+    ///   ordinal (`2018-060`) dates, optionally followed by `T`, a time
+    ///   and an offset. A date alone means midnight UTC
+    pub fn parse_lenient(input: &str) -> Result<Self> {
+        let input = input.trim();
+
+        if !input.is_empty() && input.chars().all(|c| c.is_digit(10)) {
+            return Self::from_unix_digits(input);
+        }
+
+        let input = restore_offset_sign(input);
+
+        if let Ok(time) = Self::from_str(&input) {
+            return Ok(time);
+        }
+
+        let (date, time) = match input.find(|c| c == 'T' || c == 't') {
+            Some(n) => (&input[..n], Some(&input[n + 1..])),
+            None => (&input[..], None),
+        };
+
+        let date = parse_iso_date(date).ok_or_else(|| {
+            Error::from(format!(
+                "Unrecognised time {:?}, expected RFC 3339, an ISO 8601 date or Unix seconds",
+                input
+            ))
+        })?;
+
+        match time {
+            Some(time) => Self::from_str(&format!("{}T{}", date.format("%Y-%m-%d"), time))
+                .chain_err(|| format!("Time {:?} needs seconds and an offset, e.g. `10:00:00Z`", time)),
+            None => Ok(Self::from_inner(DateTime::from_utc(date.and_hms(0, 0, 0), Utc))),
+        }
+    }
+
+    /// Unix seconds or milliseconds. Other lengths could be either, or
+    /// a typo, so they are refused rather than guessed at
+    fn from_unix_digits(digits: &str) -> Result<Self> {
+        let value = digits
+            .parse::<i64>()
+            .chain_err(|| format!("Unix time {} is out of range", digits))?;
+
+        let (secs, nanos) = match digits.len() {
+            10 => (value, 0),
+            13 => (value / 1000, (value % 1000) as u32 * 1_000_000),
+            _ => bail!(
+                "Ambiguous Unix time {}, use 10 digits for seconds or 13 for milliseconds",
+                digits
+            ),
+        };
+
+        Ok(Self::from_inner(Utc.timestamp(secs, nanos)))
+    }
+
+    pub fn from_inner(time: DateTime<Utc>) -> Self {
+        let rendered = time.to_rfc3339();
+
+        Self {
+            inner: time,
+            rendered: rendered,
+        }
+    }
+
     pub fn as_str(&self) -> &str {
         &self.rendered
     }
@@ -132,6 +206,77 @@ impl ProveWhenTime {
             rendered: rendered,
         }
     }
+
+    /// Start of the period after the one containing this time, which is
+    /// when a key generated at this time will be rotated out
+    pub fn next_period(&self) -> Self {
+        Self::from_inner(*self.floored().inner() + Duration::hours(1))
+    }
+}
+
+/// Turn `2018-03-01T12:00:00 02:00` back into `...+02:00`. A `+` in a
+/// query string or form decodes to a space
+fn restore_offset_sign(input: &str) -> String {
+    if let Some(n) = input.rfind(' ') {
+        let offset = &input[n + 1..];
+        if offset.len() == 5 && offset.as_bytes()[2] == b':' && input[..n].contains(':') {
+            return format!("{}+{}", &input[..n], offset);
+        }
+    }
+
+    input.into()
+}
+
+/// An ISO 8601 calendar, week or ordinal date, in the extended format
+fn parse_iso_date(date: &str) -> Option<NaiveDate> {
+    const WEEKDAYS: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    let number = |s: &str, len: usize| -> Option<u32> {
+        if s.len() == len && s.chars().all(|c| c.is_digit(10)) {
+            s.parse().ok()
+        } else {
+            None
+        }
+    };
+
+    let parts = date.split('-').collect::<Vec<_>>();
+    let year = match number(parts[0], 4) {
+        Some(year) => year as i32,
+        None => return None,
+    };
+
+    match parts.len() {
+        // 2018-W09-4, or 2018-W09 for its Monday
+        2 | 3 if parts[1].starts_with('W') => {
+            let day = parts.get(2).map_or(Some(1), |d| number(d, 1));
+
+            match (number(&parts[1][1..], 2), day) {
+                (Some(week), Some(day)) if day >= 1 && day <= 7 => {
+                    NaiveDate::from_isoywd_opt(year, week, WEEKDAYS[day as usize - 1])
+                }
+                _ => None,
+            }
+        }
+
+        // 2018-060
+        2 => number(parts[1], 3).and_then(|ordinal| NaiveDate::from_yo_opt(year, ordinal)),
+
+        // 2018-03-01
+        3 => match (number(parts[1], 2), number(parts[2], 2)) {
+            (Some(month), Some(day)) => NaiveDate::from_ymd_opt(year, month, day),
+            _ => None,
+        },
+
+        _ => None,
+    }
 }
 
 pub struct DateTimeRange {
@@ -175,3 +320,65 @@ impl Iterator for DateTimeRange {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lenient(input: &str) -> String {
+        ProveWhenTime::parse_lenient(input).unwrap().as_str().into()
+    }
+
+    #[test]
+    fn lenient_forms_agree() {
+        let expected = ProveWhenTime::from_str("2018-03-01T10:00:00Z").unwrap();
+
+        for input in &[
+            "2018-03-01T10:00:00Z",
+            "2018-03-01T12:00:00+02:00",
+            "2018-03-01T12:00:00 02:00",
+            "2018-03-01T05:30:00-04:30",
+            "1519898400",
+            "1519898400000",
+            "2018-W09-4T10:00:00Z",
+            "2018-060T11:00:00+01:00",
+        ] {
+            assert_eq!(lenient(input), expected.as_str(), "{}", input);
+        }
+
+        let midnight = "2018-03-01T00:00:00+00:00";
+        assert_eq!(lenient("2018-03-01"), midnight);
+        assert_eq!(lenient("2018-W09-4"), midnight);
+        assert_eq!(lenient("2018-060"), midnight);
+        assert_eq!(lenient("2018-W09"), "2018-02-26T00:00:00+00:00");
+        assert_eq!(lenient("1519898400123"), "2018-03-01T10:00:00.123+00:00");
+    }
+
+    #[test]
+    fn lenient_refuses_ambiguous() {
+        for input in &[
+            "",
+            "151989840", // 9 digits
+            "15198984001", // 11 digits
+            "2018-03-01T10:00:00", // no offset
+            "2018-W54-1",
+            "2018-W09-8",
+            "2018-366",
+            "2018-3-1",
+            "yesterday",
+        ] {
+            assert!(ProveWhenTime::parse_lenient(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn params_are_url_decoded() {
+        let param = RawStr::from_str("2018-03-01T12%3A00%3A00%2B02%3A00");
+        let time = ProveWhenTime::from_param(param).unwrap();
+        assert_eq!(time.as_str(), "2018-03-01T10:00:00+00:00");
+
+        // A literal `+` survives decoding
+        let param = RawStr::from_str("2018-03-01T12:00:00+02:00");
+        assert!(ProveWhenTime::from_param(param).is_ok());
+    }
+}
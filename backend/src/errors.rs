@@ -8,6 +8,95 @@ error_chain!{
     links {
         Mvdb(mvdb::errors::Error, mvdb::errors::ErrorKind);
     }
+
+    errors {
+        PayloadTooLarge(limit: u64) {
+            description("payload too large")
+            display("Payload exceeds the limit of {} bytes", limit)
+        }
+        FutureKey {
+            description("cannot provide future keys")
+            display("Cannot provide future keys")
+        }
+        BeforeHistory {
+            description("time is before recorded history")
+            display("Time is before recorded history")
+        }
+        KeyMismatch {
+            description("key mismatch")
+            display("Key mismatch!")
+        }
+        SignatureMismatch {
+            description("signature mismatch")
+            display("Signature mismatch!")
+        }
+        Decode {
+            description("failed to decode")
+            display("failed to decode")
+        }
+        KeyTimeMismatch {
+            description("key time mismatch")
+            display("The receipt's key_time does not match the signing key")
+        }
+        OutsideValidity {
+            description("timestamp outside of key validity")
+            display("The receipt's timestamp is outside of the signing key's validity period")
+        }
+        MalformedNonce {
+            description("malformed nonce")
+            display("The receipt's nonce is not a provewhen.io nonce")
+        }
+        KeyRevoked {
+            description("key was revoked")
+            display("The signing key was revoked")
+        }
+        ClockRegression(behind_ms: i64) {
+            description("clock went backwards")
+            display("The clock is {}ms behind receipts already issued, refusing to sign", behind_ms)
+        }
+        SigningPaused {
+            description("signing is paused")
+            display("Signing is paused for maintenance")
+        }
+        ClockOffset(offset_ms: i64) {
+            description("clock disagrees with time sources")
+            display("The clock is {}ms off from the configured time sources, refusing to sign", offset_ms)
+        }
+    }
+}
+
+impl Error {
+    /// Short, stable name for the kind of failure, used as a metrics label
+    pub fn reason(&self) -> &'static str {
+        match *self.kind() {
+            ErrorKind::PayloadTooLarge(_) => "payload_too_large",
+            ErrorKind::FutureKey => "future_key",
+            ErrorKind::BeforeHistory => "before_history",
+            ErrorKind::KeyMismatch => "key_mismatch",
+            ErrorKind::SignatureMismatch => "signature_mismatch",
+            ErrorKind::Decode => "decode",
+            ErrorKind::KeyTimeMismatch => "key_time_mismatch",
+            ErrorKind::OutsideValidity => "outside_validity",
+            ErrorKind::MalformedNonce => "malformed_nonce",
+            ErrorKind::KeyRevoked => "key_revoked",
+            ErrorKind::ClockRegression(_) => "clock_regression",
+            ErrorKind::SigningPaused => "paused",
+            ErrorKind::ClockOffset(_) => "clock_offset",
+            ErrorKind::Mvdb(_) => "keystore",
+            _ => "other",
+        }
+    }
+
+    /// HTTP status used when this error is returned from an endpoint
+    pub fn status(&self) -> Status {
+        match *self.kind() {
+            ErrorKind::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            ErrorKind::ClockRegression(_) => Status::ServiceUnavailable,
+            ErrorKind::SigningPaused => Status::ServiceUnavailable,
+            ErrorKind::ClockOffset(_) => Status::ServiceUnavailable,
+            _ => Status::BadRequest,
+        }
+    }
 }
 
 // Implement `Responder` for `error_chain`'s `Error` type
@@ -15,6 +104,7 @@ error_chain!{
 impl<'r> Responder<'r> for Error {
     fn respond_to(self, _: &Request) -> ::std::result::Result<Response<'r>, Status> {
         // Render the whole error chain to a single string
+        let status = self.status();
         let mut rslt = String::new();
 
         #[cfg(debug_assertions)]
@@ -41,7 +131,7 @@ impl<'r> Responder<'r> for Error {
         // successfully created an error response
         Ok(
             Response::build()
-                .status(Status::BadRequest)
+                .status(status)
                 .header(ContentType::JSON)
                 .sized_body(Cursor::new(resp))
                 .finalize(),
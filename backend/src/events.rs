@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use datetime_utils::ProveWhenTime;
+use key_types::TimedPublicKey;
+
+/// Something that happened to the set of published keys
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeyEvent {
+    /// A new key was put into use
+    Rotation { key: TimedPublicKey },
+
+    /// The server was not running between `from` and `to`, and `keys`
+    /// keys were back-filled to cover the missing periods
+    Gap {
+        from: ProveWhenTime,
+        to: ProveWhenTime,
+        keys: usize,
+    },
+
+    /// A key was withdrawn before the end of its period
+    Revocation {
+        key_time: ProveWhenTime,
+        public_key: String,
+        reason: String,
+    },
+}
+
+impl KeyEvent {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            KeyEvent::Rotation { .. } => "rotation",
+            KeyEvent::Gap { .. } => "gap",
+            KeyEvent::Revocation { .. } => "revocation",
+        }
+    }
+
+    /// The key time this event is about. Clients resume a stream from here
+    pub fn time(&self) -> &ProveWhenTime {
+        match *self {
+            KeyEvent::Rotation { ref key } => key.time(),
+            KeyEvent::Gap { ref to, .. } => to,
+            KeyEvent::Revocation { ref key_time, .. } => key_time,
+        }
+    }
+}
+
+/// Fans key events out to every subscriber, e.g. open event streams
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<KeyEvent>>>,
+}
+
+lazy_static! {
+    pub static ref EVENTS: EventBus = EventBus {
+        subscribers: Mutex::new(Vec::new()),
+    };
+}
+
+impl EventBus {
+    pub fn publish(&self, event: KeyEvent) {
+        // Subscribers that hung up are dropped here
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    pub fn subscribe(&self) -> Receiver<KeyEvent> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_to_live_subscribers() {
+        let bus = EventBus { subscribers: Mutex::new(Vec::new()) };
+        let rx = bus.subscribe();
+        let gone = bus.subscribe();
+        drop(gone);
+
+        let now = ProveWhenTime::now();
+        bus.publish(KeyEvent::Gap {
+            from: now.clone(),
+            to: now.clone(),
+            keys: 0,
+        });
+
+        assert_eq!(rx.recv().unwrap().name(), "gap");
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,639 @@
+//! Publishes key events outside the process: to a directory, through a
+//! command, or to a webhook. Deliveries are retried with backoff, and
+//! their state is kept on disk so failures survive restarts and show up
+//! in `/health`
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::Duration;
+use mvdb::Mvdb;
+use serde_json;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use events::KeyEvent;
+use metrics::METRICS;
+use scheduler::{SHUTDOWN, SHUTDOWN_POLL_MS};
+use settings::{Hook, HookAction, HookSettings};
+
+/// Finished deliveries kept in the state file, for the record
+const KEEP_FINISHED: usize = 100;
+
+/// Longest wait between two attempts of a delivery
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed, // gave up after `max_attempts`
+}
+
+/// One event, on its way to one hook
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Delivery {
+    pub id: u64,
+    pub hook: String,
+    pub event: KeyEvent,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub next_attempt: ProveWhenTime,
+    pub last_error: Option<String>,
+    pub updated: ProveWhenTime,
+}
+
+impl Delivery {
+    fn delivered(&mut self, now: &ProveWhenTime) {
+        self.attempts += 1;
+        self.status = DeliveryStatus::Delivered;
+        self.updated = now.clone();
+    }
+
+    /// Record a failed attempt, and schedule a retry or give up
+    fn failed(&mut self, error: String, settings: &HookSettings, now: &ProveWhenTime) {
+        self.attempts += 1;
+        self.last_error = Some(error);
+        self.updated = now.clone();
+
+        if self.attempts >= settings.max_attempts {
+            self.status = DeliveryStatus::Failed;
+            return;
+        }
+
+        let doublings = cmp::min(self.attempts - 1, 20);
+        let backoff = cmp::min(settings.retry_secs as i64 * (1 << doublings), MAX_BACKOFF_SECS);
+        self.next_attempt = ProveWhenTime::from_inner(*now.inner() + Duration::seconds(backoff));
+    }
+}
+
+/// Counts for `/health`
+#[derive(Serialize, Clone, Default)]
+pub struct Summary {
+    pub pending: usize,
+    pub failed: usize,
+    pub last_error: Option<String>,
+}
+
+/// Persisted delivery state
+#[derive(Serialize, Deserialize, Default)]
+pub struct HookState {
+    next_id: u64,
+    deliveries: Vec<Delivery>,
+}
+
+impl HookState {
+    /// Queue `event` for every hook that wants it
+    fn enqueue(&mut self, event: &KeyEvent, hooks: &[Hook], now: &ProveWhenTime) {
+        for hook in hooks.iter().filter(|h| wants(h, event)) {
+            self.deliveries.push(Delivery {
+                id: self.next_id,
+                hook: hook.name.clone(),
+                event: event.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                next_attempt: now.clone(),
+                last_error: None,
+                updated: now.clone(),
+            });
+            self.next_id += 1;
+        }
+    }
+
+    fn due(&self, now: &ProveWhenTime) -> Vec<Delivery> {
+        self.deliveries
+            .iter()
+            .filter(|d| d.status == DeliveryStatus::Pending && d.next_attempt <= *now)
+            .cloned()
+            .collect()
+    }
+
+    fn update(&mut self, delivery: Delivery) {
+        if let Some(d) = self.deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            *d = delivery;
+        }
+    }
+
+    /// Forget the oldest finished deliveries beyond `KEEP_FINISHED`
+    fn prune(&mut self) {
+        let finished = self.deliveries
+            .iter()
+            .filter(|d| d.status != DeliveryStatus::Pending)
+            .count();
+        let mut excess = finished.saturating_sub(KEEP_FINISHED);
+
+        self.deliveries.retain(|d| if excess > 0 && d.status != DeliveryStatus::Pending {
+            excess -= 1;
+            false
+        } else {
+            true
+        });
+    }
+
+    fn summary(&self) -> Summary {
+        let count = |status| self.deliveries.iter().filter(|d| d.status == status).count();
+
+        Summary {
+            pending: count(DeliveryStatus::Pending),
+            failed: count(DeliveryStatus::Failed),
+            last_error: self.deliveries
+                .iter()
+                .filter(|d| d.last_error.is_some())
+                .max_by_key(|d| d.updated.clone())
+                .map(|d| format!("{}: {}", d.hook, d.last_error.as_ref().unwrap())),
+        }
+    }
+}
+
+lazy_static! {
+    static ref SUMMARY: Mutex<Option<Summary>> = Mutex::new(None);
+}
+
+/// Delivery counts, or `None` if hooks aren't running
+pub fn summary() -> Option<Summary> {
+    SUMMARY.lock().unwrap().clone()
+}
+
+fn wants(hook: &Hook, event: &KeyEvent) -> bool {
+    hook.events.is_empty() || hook.events.iter().any(|e| e == event.name())
+}
+
+fn deliver(action: &HookAction, id: u64, event: &KeyEvent, timeout: StdDuration) -> Result<()> {
+    let body = serde_json::to_string(event).chain_err(|| "Failed to serialize event")?;
+
+    match *action {
+        HookAction::Directory { ref path } => write_to_directory(path, id, event, &body),
+        HookAction::Command {
+            ref program,
+            ref args,
+        } => run_command(program, args, event, &body, timeout),
+        HookAction::Webhook { ref url } => post(url, event, &body, timeout),
+    }
+}
+
+/// Write the event next to a temporary name first, so readers of the
+/// directory never see a partial file. The delivery id keeps events of
+/// the same type in the same second apart
+fn write_to_directory(dir: &Path, id: u64, event: &KeyEvent, body: &str) -> Result<()> {
+    fs::create_dir_all(dir).chain_err(|| format!("Failed to create {}", dir.display()))?;
+
+    let name = format!(
+        "{}-{}-{}.json",
+        event.time().inner().format("%Y%m%dT%H%M%SZ"),
+        event.name(),
+        id
+    );
+    let tmp = dir.join(format!(".{}.tmp", name));
+
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(body.as_bytes()))
+        .chain_err(|| format!("Failed to write {}", tmp.display()))?;
+
+    fs::rename(&tmp, dir.join(&name)).chain_err(|| format!("Failed to publish {}", name))
+}
+
+fn run_command(
+    program: &str,
+    args: &[String],
+    event: &KeyEvent,
+    body: &str,
+    timeout: StdDuration,
+) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .env("PROVEWHEN_EVENT", event.name())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .chain_err(|| format!("Failed to run {}", program))?;
+
+    // Commands don't have to read the event. Dropping stdin closes it
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(body.as_bytes());
+    }
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait().chain_err(|| format!("Failed to wait for {}", program))? {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => bail!("{} exited with {}", program, status),
+            None if started.elapsed() > timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("{} timed out", program);
+            }
+            None => thread::sleep(StdDuration::from_millis(50)),
+        }
+    }
+}
+
+/// Split an `http://host[:port][/path]` URL
+fn parse_http_url(url: &str) -> Result<(String, u16, String)> {
+    if !url.starts_with("http://") {
+        bail!("Only http:// webhooks are supported, not {}", url);
+    }
+
+    let rest = &url["http://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(n) => (&rest[..n], &rest[n..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rfind(':') {
+        Some(n) if !authority[n..].contains(']') => (
+            &authority[..n],
+            authority[n + 1..]
+                .parse()
+                .chain_err(|| format!("Invalid port in {}", url))?,
+        ),
+        _ => (authority, 80),
+    };
+
+    let host = host.trim_matches(|c| c == '[' || c == ']');
+    if host.is_empty() {
+        bail!("No host in {}", url);
+    }
+
+    Ok((host.into(), port, path.into()))
+}
+
+/// The `Host` header for `host`, with IPv6 literals bracketed again
+fn host_header(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn post(url: &str, event: &KeyEvent, body: &str, timeout: StdDuration) -> Result<()> {
+    let (host, port, path) = parse_http_url(url)?;
+
+    // `TcpStream::connect` could wait minutes for an unresponsive host
+    let addr = (&host[..], port)
+        .to_socket_addrs()
+        .chain_err(|| format!("Failed to resolve {}", url))?
+        .next()
+        .ok_or_else(|| Error::from(format!("{} didn't resolve to an address", url)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)
+        .chain_err(|| format!("Failed to connect to {}", url))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .chain_err(|| "Failed to set webhook timeout")?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/json\r\n\
+         X-Provewhen-Event: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        path,
+        host_header(&host, port),
+        event.name(),
+        body.len(),
+        body
+    );
+
+    stream
+        .write_all(request.as_bytes())
+        .chain_err(|| format!("Failed to send to {}", url))?;
+
+    // Only the status line matters
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .chain_err(|| format!("No response from {}", url))?;
+
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::from(format!("Malformed response from {}", url)))?;
+
+    if status / 100 != 2 {
+        bail!("{} answered {}", url, status);
+    }
+    Ok(())
+}
+
+fn publish_summary(state: &Mvdb<HookState>) -> Result<()> {
+    let summary = state.access(|s| s.summary())?;
+    *SUMMARY.lock().unwrap() = Some(summary);
+    Ok(())
+}
+
+/// Make the deliveries that are due
+fn attempt_due(state: &Mvdb<HookState>, settings: &HookSettings) -> Result<()> {
+    let due = state.access(|s| s.due(&ProveWhenTime::now()))?;
+    if due.is_empty() {
+        return Ok(());
+    }
+
+    let timeout = StdDuration::from_secs(settings.timeout_secs);
+
+    for mut delivery in due {
+        // The rest stay pending for the next run
+        if SHUTDOWN.requested() {
+            break;
+        }
+
+        let rslt = match settings.targets.iter().find(|h| h.name == delivery.hook) {
+            Some(hook) => deliver(&hook.action, delivery.id, &delivery.event, timeout),
+            None => Err("The hook is no longer configured".into()),
+        };
+
+        let now = ProveWhenTime::now();
+        match rslt {
+            Ok(()) => delivery.delivered(&now),
+            Err(e) => {
+                println!(
+                    "Hook {} failed to deliver a {} event: {}",
+                    delivery.hook,
+                    delivery.event.name(),
+                    e
+                );
+                delivery.failed(e.to_string(), settings, &now);
+
+                if delivery.status == DeliveryStatus::Failed {
+                    println!(
+                        "ALERT: hook {} gave up on a {} event after {} attempts",
+                        delivery.hook,
+                        delivery.event.name(),
+                        delivery.attempts
+                    );
+                    METRICS.hook_failures.inc(&delivery.hook);
+                }
+            }
+        }
+
+        state.access_mut(|s| s.update(delivery))?;
+    }
+
+    state.access_mut(|s| s.prune())?;
+    publish_summary(state)
+}
+
+/// Deliver `events` to the configured hooks, until the event bus closes
+/// or a shutdown is requested.
+/// Pass a receiver subscribed before the key database is defrosted, so
+/// gaps found on startup are published too
+pub fn run(settings: HookSettings, events: Receiver<KeyEvent>) -> Result<()> {
+    let state: Mvdb<HookState> = Mvdb::from_file_or_default_pretty(&settings.state_file)?;
+    publish_summary(&state)?;
+
+    while !SHUTDOWN.requested() {
+        // Deliveries left pending by a previous run are retried first
+        attempt_due(&state, &settings)?;
+
+        // Retries are checked for at least this often too
+        match events.recv_timeout(StdDuration::from_millis(SHUTDOWN_POLL_MS)) {
+            Ok(event) => {
+                let now = ProveWhenTime::now();
+                state.access_mut(|s| s.enqueue(&event, &settings.targets, &now))?;
+                publish_summary(&state)?;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use chrono::Utc;
+
+    use clock::ManualClock;
+    use events::EVENTS;
+    use pub_key_storage::KeyDB;
+    use super::*;
+
+    fn gap() -> KeyEvent {
+        let now = ProveWhenTime::from_str("2018-03-01T10:00:00Z").unwrap();
+        KeyEvent::Gap {
+            from: now.clone(),
+            to: now,
+            keys: 0,
+        }
+    }
+
+    fn hook(name: &str, events: &[&str], action: HookAction) -> Hook {
+        Hook {
+            name: name.into(),
+            events: events.iter().map(|e| e.to_string()).collect(),
+            action: action,
+        }
+    }
+
+    #[test]
+    fn retries_back_off_then_give_up() {
+        let mut settings = HookSettings::default();
+        settings.max_attempts = 3;
+        settings.retry_secs = 30;
+
+        let hooks = [
+            hook("all", &[], HookAction::Webhook { url: "http://localhost/".into() }),
+            hook("rotations", &["rotation"], HookAction::Webhook { url: "http://localhost/".into() }),
+        ];
+
+        let now = ProveWhenTime::from_str("2018-03-01T10:00:00Z").unwrap();
+        let mut state = HookState::default();
+        state.enqueue(&gap(), &hooks, &now);
+
+        let mut delivery = state.due(&now).pop().unwrap();
+        assert_eq!(state.deliveries.len(), 1);
+        assert_eq!(delivery.hook, "all");
+
+        delivery.failed("refused".into(), &settings, &now);
+        assert!(delivery.next_attempt == ProveWhenTime::from_str("2018-03-01T10:00:30Z").unwrap());
+
+        delivery.failed("refused".into(), &settings, &now);
+        assert!(delivery.next_attempt == ProveWhenTime::from_str("2018-03-01T10:01:00Z").unwrap());
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+
+        delivery.failed("refused".into(), &settings, &now);
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+
+        state.update(delivery);
+        let summary = state.summary();
+        assert_eq!((summary.pending, summary.failed), (0, 1));
+        assert_eq!(summary.last_error, Some("all: refused".into()));
+    }
+
+    #[test]
+    fn directory_hook() {
+        let dir = env::temp_dir().join(format!(
+            "provewhen-hooks-{}",
+            Utc::now().timestamp_subsec_nanos()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+
+        let action = HookAction::Directory { path: dir.clone() };
+        deliver(&action, 7, &gap(), StdDuration::from_secs(5)).unwrap();
+        deliver(&action, 8, &gap(), StdDuration::from_secs(5)).unwrap();
+
+        let mut written = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        written.sort();
+        assert_eq!(
+            written,
+            vec![
+                "20180301T100000Z-gap-7.json".to_string(),
+                "20180301T100000Z-gap-8.json".to_string(),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restart_publishes_key_in_use() {
+        let dir = env::temp_dir().join(format!(
+            "provewhen-restart-hooks-{}",
+            Utc::now().timestamp_subsec_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let bus = EVENTS.subscribe();
+
+        let clock = Arc::new(ManualClock::at("2019-06-01T10:15:00+00:00"));
+        let mut kdb = KeyDB::with_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+        let stored = serde_json::to_string(&kdb).unwrap();
+
+        // Restarted a couple of hours later
+        clock.set(*ProveWhenTime::from_str("2019-06-01T12:20:00+00:00").unwrap().inner());
+        let mut kdb: KeyDB = serde_json::from_str(&stored).unwrap();
+        kdb.set_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+
+        // Other tests publish on the same bus
+        let (tx, events) = channel();
+        for event in bus.try_iter().filter(|e| e.time().as_str().starts_with("2019-06-01")) {
+            tx.send(event).unwrap();
+        }
+        drop(tx);
+
+        let out = dir.join("out");
+        let settings = HookSettings {
+            targets: vec![hook("keys", &["rotation"], HookAction::Directory { path: out.clone() })],
+            state_file: dir.join("hooks_state.json"),
+            ..HookSettings::default()
+        };
+        run(settings, events).unwrap();
+
+        let mut written = fs::read_dir(&out)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        written.sort();
+        assert_eq!(
+            written,
+            vec![
+                "20190601T101500Z-rotation-0.json".to_string(),
+                "20190601T110000Z-rotation-1.json".to_string(),
+                "20190601T120000Z-rotation-2.json".to_string(),
+                "20190601T122000Z-rotation-3.json".to_string(),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_hook() {
+        let check = HookAction::Command {
+            program: "sh".into(),
+            args: vec!["-c".into(), "test \"$PROVEWHEN_EVENT\" = gap".into()],
+        };
+        assert!(deliver(&check, 1, &gap(), StdDuration::from_secs(5)).is_ok());
+
+        let fail = HookAction::Command {
+            program: "false".into(),
+            args: Vec::new(),
+        };
+        assert!(deliver(&fail, 1, &gap(), StdDuration::from_secs(5)).is_err());
+    }
+
+    /// A stand-in webhook receiver that answers one request with `status`
+    fn receiver(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/provewhen", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if line.to_lowercase().starts_with("content-length:") {
+                    length = line[15..].trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            assert!(serde_json::from_slice::<KeyEvent>(&body).is_ok());
+
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+        });
+
+        url
+    }
+
+    #[test]
+    fn webhook_hook() {
+        let ok = HookAction::Webhook { url: receiver("204 No Content") };
+        assert!(deliver(&ok, 1, &gap(), StdDuration::from_secs(5)).is_ok());
+
+        let failing = HookAction::Webhook { url: receiver("500 Internal Server Error") };
+        assert!(deliver(&failing, 1, &gap(), StdDuration::from_secs(5)).is_err());
+
+        let https = HookAction::Webhook { url: "https://example.com/".into() };
+        assert!(deliver(&https, 1, &gap(), StdDuration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn webhook_urls() {
+        assert_eq!(
+            parse_http_url("http://example.com:8080/a/b").unwrap(),
+            ("example.com".into(), 8080, "/a/b".into())
+        );
+        assert_eq!(
+            parse_http_url("http://[::1]").unwrap(),
+            ("::1".into(), 80, "/".into())
+        );
+        assert!(parse_http_url("http://example.com:port/").is_err());
+
+        assert_eq!(host_header("example.com", 8080), "example.com:8080");
+        assert_eq!(host_header("::1", 80), "[::1]:80");
+    }
+}
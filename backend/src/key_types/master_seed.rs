@@ -0,0 +1,261 @@
+//! Deterministic keys. Each key is derived with HKDF from a master seed,
+//! the start of its period and a generation counter, so every key can be
+//! regenerated from a backup of the seed alone. The seed is kept on disk
+//! sealed with a passphrase
+
+use std::env;
+use std::io::Write;
+use std::ops::Deref;
+use std::path::Path;
+
+use base64;
+use mvdb::helpers::just_load;
+use ring::{aead, digest, hkdf, hmac, pbkdf2};
+use ring::rand::SecureRandom;
+use serde_json;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use file_utils::create_secret_file;
+use key_types::{SingleKeySet, TimedPublicKey, RANDOM};
+use settings::KeySeedSettings;
+
+pub const SEED_LEN: usize = 32;
+
+/// HKDF salt. Changing it changes every derived key
+const DERIVATION_SALT: &[u8] = b"provewhen.io key derivation v1";
+
+/// Authenticated along with the sealed seed
+const SEALED_AD: &[u8] = b"provewhen.io master seed v1";
+const SEALED_VERSION: u32 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+pub struct MasterSeed {
+    bytes: [u8; SEED_LEN],
+}
+
+impl MasterSeed {
+    pub fn generate() -> Result<Self> {
+        let mut bytes = [0u8; SEED_LEN];
+        RANDOM
+            .deref()
+            .fill(&mut bytes[..])
+            .chain_err(|| "Failed to generate master seed")?;
+
+        Ok(MasterSeed { bytes: bytes })
+    }
+
+    /// The Ed25519 seed of the `generation`th key of the period `time`
+    /// falls in
+    fn key_seed(&self, time: &ProveWhenTime, generation: u32) -> [u8; SEED_LEN] {
+        let info = format!("key;{};{}", time.floored().inner().timestamp(), generation);
+
+        let salt = hmac::SigningKey::new(&digest::SHA256, DERIVATION_SALT);
+        let mut out = [0u8; SEED_LEN];
+        hkdf::extract_and_expand(&salt, &self.bytes, info.as_bytes(), &mut out);
+        out
+    }
+
+    /// The key for `time`, the `generation`th made in its period. Keys
+    /// made on time at period boundaries are generation 0, restarts and
+    /// forced rotations make further generations (see `generation`). The
+    /// exact `time` doesn't go into the key
+    pub fn derive(&self, time: ProveWhenTime, generation: u32) -> SingleKeySet {
+        let seed = self.key_seed(&time, generation);
+        SingleKeySet::from_seed(time, &seed).expect("derived seeds have the Ed25519 seed length")
+    }
+
+    /// The keys for every period from `start` to `end`, `generations` of
+    /// each. Regenerates the public keys without the keystore
+    pub fn derive_range(
+        &self,
+        start: &ProveWhenTime,
+        end: &ProveWhenTime,
+        generations: u32,
+    ) -> Vec<SingleKeySet> {
+        let mut keys = Vec::new();
+        let mut period = start.floored();
+
+        while period <= *end {
+            for generation in 0..generations {
+                keys.push(self.derive(period.clone(), generation));
+            }
+            period = period.next_period();
+        }
+
+        keys
+    }
+
+    /// Whether each of `keys`, in order, was derived from this seed
+    pub fn check(&self, keys: &[TimedPublicKey]) -> Vec<bool> {
+        keys.iter()
+            .enumerate()
+            .map(|(n, key)| {
+                let derived = self.derive(key.time().clone(), generation(&keys[..n], key.time()));
+                derived.pub_key_base64 == key.public_key()
+            })
+            .collect()
+    }
+
+    /// Encrypt the seed with a key stretched from `passphrase`
+    pub fn seal(&self, passphrase: &str, iterations: u32) -> Result<SealedSeed> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        RANDOM
+            .deref()
+            .fill(&mut salt[..])
+            .and_then(|_| RANDOM.deref().fill(&mut nonce[..]))
+            .chain_err(|| "Failed to generate sealing salt")?;
+
+        let key = sealing_key(passphrase, &salt, iterations);
+        let key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key)
+            .chain_err(|| "Failed to create sealing key")?;
+
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        let mut in_out = self.bytes.to_vec();
+        in_out.extend(vec![0u8; tag_len]);
+
+        let len = aead::seal_in_place(&key, &nonce, SEALED_AD, &mut in_out, tag_len)
+            .chain_err(|| "Failed to seal master seed")?;
+        in_out.truncate(len);
+
+        Ok(SealedSeed {
+            version: SEALED_VERSION,
+            iterations: iterations,
+            salt: base64::encode(&salt[..]),
+            nonce: base64::encode(&nonce[..]),
+            ciphertext: base64::encode(&in_out),
+        })
+    }
+}
+
+/// Generation of a new key for `time`, given the keys logged before it.
+/// Counts the keys already logged in its period, plus every key after
+/// the first in the period before: those may be forced rotations, which
+/// discard the key announced for this period, so it must not be derived
+/// again
+pub fn generation(logged: &[TimedPublicKey], time: &ProveWhenTime) -> u32 {
+    let period = time.floored();
+    let same = logged.iter().filter(|k| k.time().floored() == period).count();
+    let before = logged
+        .iter()
+        .filter(|k| k.time().floored().next_period() == period)
+        .count();
+
+    (same + before.saturating_sub(1)) as u32
+}
+
+fn sealing_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(&digest::SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    key
+}
+
+/// The master seed, encrypted with ChaCha20-Poly1305 under a key derived
+/// from a passphrase with PBKDF2-SHA256
+#[derive(Serialize, Deserialize)]
+pub struct SealedSeed {
+    pub version: u32,
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl SealedSeed {
+    pub fn open(&self, passphrase: &str) -> Result<MasterSeed> {
+        if self.version != SEALED_VERSION {
+            bail!("Unsupported sealed seed version {}", self.version);
+        }
+
+        let salt = base64::decode(&self.salt).chain_err(|| ErrorKind::Decode)?;
+        let nonce = base64::decode(&self.nonce).chain_err(|| ErrorKind::Decode)?;
+        let mut in_out = base64::decode(&self.ciphertext).chain_err(|| ErrorKind::Decode)?;
+
+        let key = sealing_key(passphrase, &salt, self.iterations);
+        let key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key)
+            .chain_err(|| "Failed to create opening key")?;
+
+        let plain = aead::open_in_place(&key, &nonce, SEALED_AD, 0, &mut in_out)
+            .chain_err(|| "Wrong passphrase, or the sealed seed is damaged")?;
+
+        if plain.len() != SEED_LEN {
+            bail!("The sealed seed has the wrong length");
+        }
+
+        let mut bytes = [0u8; SEED_LEN];
+        bytes.copy_from_slice(plain);
+        Ok(MasterSeed { bytes: bytes })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        just_load(path).chain_err(|| format!("Failed to load the sealed seed from {}", path.display()))
+    }
+
+    /// Write to a new file, readable by its owner only
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if path.exists() {
+            bail!("{} already exists, refusing to replace a master seed", path.display());
+        }
+
+        let json = serde_json::to_string_pretty(self).chain_err(|| "Failed to encode sealed seed")?;
+        create_secret_file(path)
+            .and_then(|mut f| f.write_all(json.as_bytes()))
+            .chain_err(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// The passphrase, from the environment variable named in `settings`
+pub fn passphrase(settings: &KeySeedSettings) -> Result<String> {
+    match env::var(&settings.passphrase_env) {
+        Ok(ref p) if !p.is_empty() => Ok(p.clone()),
+        _ => bail!("Set {} to the master seed's passphrase", settings.passphrase_env),
+    }
+}
+
+/// Load and open the configured master seed
+pub fn unseal(settings: &KeySeedSettings) -> Result<MasterSeed> {
+    SealedSeed::load(&settings.seed_file)?.open(&passphrase(settings)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::at;
+
+    #[test]
+    fn keys_are_reproducible() {
+        let seed = MasterSeed::generate().unwrap();
+
+        // Only the period and the generation matter
+        let a = seed.derive(at("2018-03-01T10:00:00Z"), 0);
+        let b = seed.derive(at("2018-03-01T11:40:00+01:00"), 0);
+        assert_eq!(a.pub_key_base64, b.pub_key_base64);
+        assert!(b.time_generated == at("2018-03-01T10:40:00Z"));
+
+        let forced = seed.derive(at("2018-03-01T10:00:00Z"), 1);
+        assert!(a.pub_key_base64 != forced.pub_key_base64);
+
+        let later = seed.derive(at("2018-03-01T11:00:00Z"), 0);
+        assert!(a.pub_key_base64 != later.pub_key_base64);
+
+        let other = MasterSeed::generate().unwrap();
+        assert!(other.derive(at("2018-03-01T10:00:00Z"), 0).pub_key_base64 != a.pub_key_base64);
+    }
+
+    #[test]
+    fn sealing_round_trip() {
+        let seed = MasterSeed::generate().unwrap();
+        let sealed = seed.seal("correct horse", 1000).unwrap();
+
+        let opened = sealed.open("correct horse").unwrap();
+        assert_eq!(opened.bytes, seed.bytes);
+
+        assert!(sealed.open("battery staple").is_err());
+
+        let mut damaged = sealed;
+        damaged.iterations += 1;
+        assert!(damaged.open("correct horse").is_err());
+    }
+}
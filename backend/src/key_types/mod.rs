@@ -1,42 +1,155 @@
 use std::ops::Deref;
-use std::path::PathBuf;
 
 use base64;
+use chrono::Duration;
+use ring::digest;
 use ring::rand::{SecureRandom, SystemRandom};
-use mvdb::helpers::just_load;
+use ring::signature;
+use untrusted;
+use rocket::http::RawStr;
+use rocket::request::FromParam;
 
 use datetime_utils::ProveWhenTime;
 use errors::*;
 
 mod timed_public_key;
 mod single_key_set;
+mod master_seed;
+mod receipt_token;
 
 // Re-export types
-pub use self::timed_public_key::TimedPublicKey;
+pub use self::timed_public_key::{KeyProof, KeyStatement, TimedPublicKey, KEY_ALGORITHM};
 pub use self::single_key_set::SingleKeySet;
+pub use self::master_seed::{generation, unseal, passphrase, MasterSeed, SealedSeed};
+pub use self::receipt_token::{ReceiptClaims, ReceiptToken, TokenFormat};
+
+/// Receipts issued before `version` was added are version 1, and are
+/// verified leniently by default
+pub const RECEIPT_VERSION: u32 = 2;
+
+/// Prefix of every nonce generated by `nonce()`
+pub const NONCE_PREFIX: &str = "provewhen.io:";
+
+/// Bytes of random data in a nonce
+pub const NONCE_BYTES: usize = 32;
+
+fn legacy_receipt_version() -> u32 {
+    1
+}
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct SignResponse {
+    // Selects the signed format and the default verification mode.
+    // Changing it changes the signed string, so it can't be dropped to
+    // get lenient verification
+    #[serde(default = "legacy_receipt_version")]
+    pub version: u32,
+
     pub timestamp: ProveWhenTime, // rfc3339 timestamp
     pub key_time: ProveWhenTime,  // rfc3339 timestamp
     pub public_key: String,       // base64 encoded Ed25519 public key
     pub message: String,          // utf8 data
     pub signature: String,        // base64 encoded Ed25519 signature
     pub nonce: String,            // "provewhen.io:<256bits of random as base64>"
+
+    /// Bound on the error of `timestamp`, measured against the time
+    /// sources. Signed from version 2 on, and absent before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy_ms: Option<u64>,
 }
 
-#[derive(Deserialize)]
-struct ProofMessages {
-    messages: Vec<String>,
+impl SignResponse {
+    /// The interval the message was signed in, `timestamp` give or take
+    /// the accuracy. `None` for receipts that don't state an accuracy
+    pub fn interval(&self) -> Option<(ProveWhenTime, ProveWhenTime)> {
+        self.accuracy_ms.map(|ms| {
+            let accuracy = Duration::milliseconds(ms as i64);
+            (
+                ProveWhenTime::from_inner(*self.timestamp.inner() - accuracy),
+                ProveWhenTime::from_inner(*self.timestamp.inner() + accuracy),
+            )
+        })
+    }
 }
 
-// lazy-load random text strings used for proof messages
-lazy_static! {
-    pub static ref PROOF_MESSAGES: Vec<String> = {
-        let fname = PathBuf::from("../tt_snips.json");
-        let x: ProofMessages = just_load(&fname).expect("failed to load snips");
-        x.messages
-    };
+/// How thoroughly `KeyDB::verify` checks a receipt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Signature and key only. Receipts from older servers may not
+    /// pass anything stricter
+    Lenient,
+    /// Also check `key_time`, that `timestamp` falls in the key's
+    /// validity period, and the form of the nonce
+    Strict,
+}
+
+impl VerifyMode {
+    /// The default mode for a receipt, based on its version
+    pub fn for_receipt(receipt: &SignResponse) -> Self {
+        if receipt.version >= 2 {
+            VerifyMode::Strict
+        } else {
+            VerifyMode::Lenient
+        }
+    }
+}
+
+impl<'r> FromParam<'r> for VerifyMode {
+    type Error = &'r RawStr;
+
+    fn from_param(param: &'r RawStr) -> ::std::result::Result<Self, Self::Error> {
+        match param.as_str() {
+            "strict" => Ok(VerifyMode::Strict),
+            "lenient" => Ok(VerifyMode::Lenient),
+            _ => Err(param),
+        }
+    }
+}
+
+/// Whether `nonce` looks like one made by `nonce()`
+pub fn check_nonce(nonce: &str) -> Result<()> {
+    if !nonce.starts_with(NONCE_PREFIX) {
+        bail!(ErrorKind::MalformedNonce);
+    }
+
+    match base64::decode(&nonce[NONCE_PREFIX.len()..]) {
+        Ok(ref data) if data.len() == NONCE_BYTES => Ok(()),
+        _ => bail!(ErrorKind::MalformedNonce),
+    }
+}
+
+/// A signed reading of the server clock, bound to a nonce chosen by the client
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TimeResponse {
+    pub time: ProveWhenTime,     // rfc3339 timestamp
+    pub accuracy_ms: u64,        // how far `time` may be off, in either direction
+    pub client_nonce: String,    // echoed from the request
+    pub key_time: ProveWhenTime, // rfc3339 timestamp
+    pub public_key: String,      // base64 encoded Ed25519 public key
+    pub signature: String,       // base64 encoded Ed25519 signature
+}
+
+/// Longest nonce accepted from a client
+pub const MAX_CLIENT_NONCE_LEN: usize = 128;
+
+/// Client nonces are limited to characters of the common base64 and hex
+/// alphabets, so they can't be confused with the delimiters of the
+/// signed string
+pub fn check_client_nonce(client_nonce: &str) -> Result<()> {
+    let allowed = |c: char| (c.is_ascii() && c.is_alphanumeric()) || "-_+=.".contains(c);
+
+    if client_nonce.is_empty() || client_nonce.len() > MAX_CLIENT_NONCE_LEN {
+        bail!("Nonce must be between 1 and {} characters", MAX_CLIENT_NONCE_LEN);
+    }
+    if !client_nonce.chars().all(allowed) {
+        bail!("Nonce may only contain letters, digits and '-_+=.'");
+    }
+
+    Ok(())
+}
+
+pub fn time_to_signable(time: &ProveWhenTime, accuracy_ms: u64, client_nonce: &str) -> String {
+    format!("time;{};accuracy_ms={};nonce={}", time.as_str(), accuracy_ms, client_nonce)
 }
 
 // lazy-load a "global" random number generator
@@ -51,16 +164,64 @@ lazy_static! {
 
 pub fn nonce() -> Result<String> {
     // Generate a nonce from 256 bits of random data
-    let mut data = [0u8; 32];
+    let mut data = [0u8; NONCE_BYTES];
     RANDOM
         .deref()
         .fill(&mut data[..])
         .chain_err(|| "Failed to generate nonce")?;
-    Ok(format!("provewhen.io:{}", base64::encode(&data[..])))
+    Ok(format!("{}{}", NONCE_PREFIX, base64::encode(&data[..])))
+}
+
+
+
+/// Check a base64 encoded Ed25519 signature over `msg`
+pub fn verify_base64(pub_key_base64: &str, msg: &str, sig_base64: &str) -> Result<()> {
+    let pk = base64::decode(pub_key_base64).chain_err(|| ErrorKind::Decode)?;
+    let sig = base64::decode(sig_base64).chain_err(|| ErrorKind::Decode)?;
+
+    signature::verify(
+        &signature::ED25519,
+        untrusted::Input::from(&pk),
+        untrusted::Input::from(msg.as_bytes()),
+        untrusted::Input::from(&sig),
+    ).chain_err(|| ErrorKind::SignatureMismatch)
+}
+
+/// The string a receipt's signature covers. Version 1 receipts keep the
+/// original `timestamp;message;nonce`, and don't cover an accuracy.
+/// Later versions name the version and prefix each field with its length
+/// in bytes, so no field can be re-split into its neighbours
+pub fn raw_msg_to_signable(
+    version: u32,
+    timestamp: &ProveWhenTime,
+    message: &str,
+    nonce: &str,
+    accuracy_ms: Option<u64>,
+) -> String {
+    if version < 2 {
+        return format!("{};{};{}", timestamp.as_str(), message, nonce);
+    }
+
+    let accuracy = accuracy_ms.map(|ms| ms.to_string()).unwrap_or_default();
+    let fields = [timestamp.as_str(), message, nonce, &accuracy[..]];
+
+    let mut signable = format!("receipt;v{}", version);
+    for field in fields.iter() {
+        signable.push_str(&format!(";{}:{}", field.len(), field));
+    }
+    signable
 }
 
+/// Stable key identifier: the RFC 7638 JWK thumbprint of the Ed25519 key
+pub fn key_id(pub_key_base64: &str) -> Result<String> {
+    let pk = base64::decode(pub_key_base64).chain_err(|| "failed to decode")?;
 
+    // Members in lexicographic order, no whitespace, per RFC 7638
+    let canonical = format!(
+        r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#,
+        base64::encode_config(&pk, base64::URL_SAFE_NO_PAD)
+    );
 
-pub fn raw_msg_to_signable(timestamp: &ProveWhenTime, message: &str, nonce: &str) -> String {
-    format!("{};{};{}", timestamp.as_str(), message, nonce)
+    let hash = digest::digest(&digest::SHA256, canonical.as_bytes());
+    Ok(base64::encode_config(hash.as_ref(), base64::URL_SAFE_NO_PAD))
 }
@@ -0,0 +1,328 @@
+use std::io::Cursor;
+
+use base64;
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::{FromParam, Request};
+use rocket::response::{self, Responder, Response};
+use serde_cbor;
+use serde_json;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use key_types::SignResponse;
+
+/// Standard envelopes a receipt can be issued in, instead of the
+/// provewhen specific `SignResponse` JSON object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenFormat {
+    /// JWS compact serialization, RFC 7515, signed with EdDSA (RFC 8037)
+    Jws,
+    /// COSE_Sign1, RFC 8152, signed with EdDSA
+    Cose,
+}
+
+impl<'r> FromParam<'r> for TokenFormat {
+    type Error = &'r RawStr;
+
+    fn from_param(param: &'r RawStr) -> ::std::result::Result<Self, Self::Error> {
+        match param.as_str() {
+            "jws" => Ok(TokenFormat::Jws),
+            "cose" => Ok(TokenFormat::Cose),
+            _ => Err(param),
+        }
+    }
+}
+
+/// The signed content of a receipt token. These are the same fields as
+/// a `SignResponse`, minus the parts carried by the envelope itself
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ReceiptClaims {
+    pub iat: i64,                 // seconds since the unix epoch
+    pub timestamp: ProveWhenTime, // rfc3339 timestamp
+    pub key_time: ProveWhenTime,  // rfc3339 timestamp
+    pub message: String,          // utf8 data
+    pub nonce: String,            // "provewhen.io:<256bits of random as base64>"
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy_ms: Option<u64>,
+}
+
+impl ReceiptClaims {
+    pub fn from_response(resp: &SignResponse) -> Self {
+        ReceiptClaims {
+            iat: resp.timestamp.inner().timestamp(),
+            timestamp: resp.timestamp.clone(),
+            key_time: resp.key_time.clone(),
+            message: resp.message.clone(),
+            nonce: resp.nonce.clone(),
+            accuracy_ms: resp.accuracy_ms,
+        }
+    }
+}
+
+/// A finished token, ready to be handed to the client
+pub struct ReceiptToken {
+    pub format: TokenFormat,
+    pub bytes: Vec<u8>,
+}
+
+impl ReceiptToken {
+    /// Wrap `claims` in the requested envelope. `kid` identifies the
+    /// signing key, and `sign` produces a raw Ed25519 signature
+    pub fn seal<F>(format: TokenFormat, kid: &str, claims: &ReceiptClaims, sign: F) -> Result<Self>
+    where
+        F: Fn(&[u8]) -> Vec<u8>,
+    {
+        let bytes = match format {
+            TokenFormat::Jws => jws(kid, claims, sign)?.into_bytes(),
+            TokenFormat::Cose => cose_sign1(kid, claims, sign)?,
+        };
+
+        Ok(ReceiptToken {
+            format: format,
+            bytes: bytes,
+        })
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self.format {
+            TokenFormat::Jws => ContentType::new("application", "jose"),
+            TokenFormat::Cose => ContentType::new("application", "cose"),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ReceiptToken {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Ok(
+            Response::build()
+                .status(Status::Ok)
+                .header(self.content_type())
+                .sized_body(Cursor::new(self.bytes))
+                .finalize(),
+        )
+    }
+}
+
+fn b64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn jws<F>(kid: &str, claims: &ReceiptClaims, sign: F) -> Result<String>
+where
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    let header = json!({
+        "alg": "EdDSA",
+        "typ": "JWT",
+        "kid": kid,
+    });
+
+    let header = serde_json::to_vec(&header).chain_err(|| "Failed to encode JWS header")?;
+    let payload = serde_json::to_vec(claims).chain_err(|| "Failed to encode JWS payload")?;
+
+    let signing_input = format!("{}.{}", b64url(&header), b64url(&payload));
+    let sig = sign(signing_input.as_bytes());
+
+    Ok(format!("{}.{}", signing_input, b64url(&sig)))
+}
+
+// COSE header labels and values, RFC 8152 sections 3.1 and 8.2
+const COSE_HEADER_ALG: u64 = 1;
+const COSE_HEADER_KID: u64 = 4;
+const COSE_ALG_EDDSA: i64 = -8;
+const COSE_SIGN1_TAG: u64 = 18;
+
+fn cose_sign1<F>(kid: &str, claims: &ReceiptClaims, sign: F) -> Result<Vec<u8>>
+where
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    // Protected header: { alg: EdDSA, kid: <kid> }
+    let mut protected = Vec::new();
+    cbor::map(&mut protected, 2);
+    cbor::uint(&mut protected, COSE_HEADER_ALG);
+    cbor::int(&mut protected, COSE_ALG_EDDSA);
+    cbor::uint(&mut protected, COSE_HEADER_KID);
+    cbor::bytes(&mut protected, kid.as_bytes());
+
+    let payload = serde_cbor::to_vec(claims).chain_err(|| "Failed to encode COSE payload")?;
+
+    // Sig_structure = ["Signature1", protected, external_aad, payload]
+    let mut to_sign = Vec::new();
+    cbor::array(&mut to_sign, 4);
+    cbor::text(&mut to_sign, "Signature1");
+    cbor::bytes(&mut to_sign, &protected);
+    cbor::bytes(&mut to_sign, &[]);
+    cbor::bytes(&mut to_sign, &payload);
+
+    let sig = sign(&to_sign);
+
+    // COSE_Sign1 = #6.18([protected, unprotected, payload, signature])
+    let mut out = Vec::new();
+    cbor::tag(&mut out, COSE_SIGN1_TAG);
+    cbor::array(&mut out, 4);
+    cbor::bytes(&mut out, &protected);
+    cbor::map(&mut out, 0);
+    cbor::bytes(&mut out, &payload);
+    cbor::bytes(&mut out, &sig);
+
+    Ok(out)
+}
+
+/// Just enough of a CBOR encoder (RFC 7049) to build COSE structures
+/// byte-for-byte, since their signatures cover the exact encoding
+mod cbor {
+    fn head(out: &mut Vec<u8>, major: u8, value: u64) {
+        let major = major << 5;
+        if value < 24 {
+            out.push(major | value as u8);
+        } else if value <= 0xFF {
+            out.push(major | 24);
+            out.push(value as u8);
+        } else if value <= 0xFFFF {
+            out.push(major | 25);
+            out.extend_from_slice(&[(value >> 8) as u8, value as u8]);
+        } else if value <= 0xFFFF_FFFF {
+            out.push(major | 26);
+            for shift in &[24, 16, 8, 0] {
+                out.push((value >> *shift) as u8);
+            }
+        } else {
+            out.push(major | 27);
+            for shift in &[56, 48, 40, 32, 24, 16, 8, 0] {
+                out.push((value >> *shift) as u8);
+            }
+        }
+    }
+
+    pub fn uint(out: &mut Vec<u8>, value: u64) {
+        head(out, 0, value);
+    }
+
+    pub fn int(out: &mut Vec<u8>, value: i64) {
+        if value < 0 {
+            head(out, 1, (-1 - value) as u64);
+        } else {
+            head(out, 0, value as u64);
+        }
+    }
+
+    pub fn bytes(out: &mut Vec<u8>, data: &[u8]) {
+        head(out, 2, data.len() as u64);
+        out.extend_from_slice(data);
+    }
+
+    pub fn text(out: &mut Vec<u8>, data: &str) {
+        head(out, 3, data.len() as u64);
+        out.extend_from_slice(data.as_bytes());
+    }
+
+    pub fn array(out: &mut Vec<u8>, len: u64) {
+        head(out, 4, len);
+    }
+
+    pub fn map(out: &mut Vec<u8>, len: u64) {
+        head(out, 5, len);
+    }
+
+    pub fn tag(out: &mut Vec<u8>, tag: u64) {
+        head(out, 6, tag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature;
+    use untrusted;
+
+    use super::*;
+    use key_types::SingleKeySet;
+
+    fn claims_and_key() -> (SingleKeySet, ReceiptClaims) {
+        let key = SingleKeySet::new();
+        let resp = key.sign(ProveWhenTime::now(), "This is a test of tokens", Some(250)).unwrap();
+        let claims = ReceiptClaims::from_response(&resp);
+        (key, claims)
+    }
+
+    #[test]
+    fn jws_verifies() {
+        let (key, claims) = claims_and_key();
+        let token = ReceiptToken::seal(TokenFormat::Jws, &key.kid(), &claims, |m| key.sign_bytes(m))
+            .unwrap();
+        let token = String::from_utf8(token.bytes).unwrap();
+
+        let parts = token.split('.').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 3);
+
+        let sig = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+        let pk = base64::decode(&key.pub_key_base64).unwrap();
+        let signing_input = format!("{}.{}", parts[0], parts[1]);
+
+        assert!(
+            signature::verify(
+                &signature::ED25519,
+                untrusted::Input::from(&pk),
+                untrusted::Input::from(signing_input.as_bytes()),
+                untrusted::Input::from(&sig),
+            ).is_ok()
+        );
+
+        let payload = base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD).unwrap();
+        let decoded: ReceiptClaims = serde_json::from_slice(&payload).unwrap();
+        assert!(decoded == claims);
+    }
+
+    #[test]
+    fn cose_verifies() {
+        let (key, claims) = claims_and_key();
+        let token = ReceiptToken::seal(TokenFormat::Cose, &key.kid(), &claims, |m| key.sign_bytes(m))
+            .unwrap();
+
+        // Tag 18 fits in the initial byte
+        assert_eq!(token.bytes[0], 0xD2);
+        let parts = match serde_cbor::from_slice(&token.bytes[1..]).unwrap() {
+            serde_cbor::Value::Array(parts) => parts,
+            other => panic!("COSE_Sign1 should be an array, not {:?}", other),
+        };
+        assert_eq!(parts.len(), 4);
+
+        let bytes = |value: &serde_cbor::Value| match *value {
+            serde_cbor::Value::Bytes(ref b) => b.clone(),
+            ref other => panic!("expected a byte string, not {:?}", other),
+        };
+        let (protected, payload, sig) = (bytes(&parts[0]), bytes(&parts[2]), bytes(&parts[3]));
+
+        // Sig_structure, RFC 8152 section 4.4
+        let mut to_verify = Vec::new();
+        cbor::array(&mut to_verify, 4);
+        cbor::text(&mut to_verify, "Signature1");
+        cbor::bytes(&mut to_verify, &protected);
+        cbor::bytes(&mut to_verify, &[]);
+        cbor::bytes(&mut to_verify, &payload);
+
+        let pk = base64::decode(&key.pub_key_base64).unwrap();
+        assert!(
+            signature::verify(
+                &signature::ED25519,
+                untrusted::Input::from(&pk),
+                untrusted::Input::from(&to_verify),
+                untrusted::Input::from(&sig),
+            ).is_ok()
+        );
+
+        let decoded: ReceiptClaims = serde_cbor::from_slice(&payload).unwrap();
+        assert!(decoded == claims);
+    }
+
+    #[test]
+    fn cbor_heads() {
+        let mut out = Vec::new();
+        cbor::uint(&mut out, 23);
+        cbor::uint(&mut out, 24);
+        cbor::uint(&mut out, 500);
+        cbor::int(&mut out, -8);
+        cbor::tag(&mut out, 18);
+        assert_eq!(out, vec![0x17, 0x18, 0x18, 0x19, 0x01, 0xF4, 0x27, 0xD2]);
+    }
+}
@@ -5,8 +5,10 @@ use ring::signature;
 use untrusted;
 
 use datetime_utils::ProveWhenTime;
-use key_types::{SignResponse, raw_msg_to_signable, nonce, RANDOM};
-use errors::Result;
+use key_types::{check_client_nonce, key_id, ReceiptClaims, ReceiptToken, SignResponse,
+                TimeResponse, TokenFormat, raw_msg_to_signable, time_to_signable, nonce,
+                RANDOM, RECEIPT_VERSION};
+use errors::*;
 
 pub struct SingleKeySet {
     pub time_generated: ProveWhenTime,
@@ -40,31 +42,91 @@ impl SingleKeySet {
         }
     }
 
+    /// The key with the given 32 byte Ed25519 seed, for keys derived
+    /// from a master seed
+    pub fn from_seed(time: ProveWhenTime, seed: &[u8]) -> Result<Self> {
+        let key_pair = signature::Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(seed))
+            .chain_err(|| "Invalid Ed25519 seed")?;
+
+        let pk = base64::encode(key_pair.public_key_bytes());
+
+        Ok(SingleKeySet {
+            time_generated: time,
+            pub_key_base64: pk,
+            rendered_kp: key_pair,
+        })
+    }
+
+    pub fn public_key_bytes(&self) -> &[u8] {
+        self.keypair().public_key_bytes()
+    }
+
     fn keypair(&self) -> &signature::Ed25519KeyPair {
         &self.rendered_kp
     }
 
-    fn sign_base64(&self, msg: &str) -> String {
-        base64::encode(&self.keypair().sign(msg.as_bytes()))
+    /// Raw Ed25519 signature over `msg`
+    pub fn sign_bytes(&self, msg: &[u8]) -> Vec<u8> {
+        self.keypair().sign(msg).as_ref().to_vec()
+    }
+
+    pub fn sign_base64(&self, msg: &str) -> String {
+        base64::encode(&self.sign_bytes(msg.as_bytes()))
     }
 
-    pub fn sign(&self, now: ProveWhenTime, msg: &str) -> Result<SignResponse> {
+    pub fn kid(&self) -> String {
+        key_id(&self.pub_key_base64).expect("own public key is valid base64")
+    }
+
+    pub fn sign(&self, now: ProveWhenTime, msg: &str, accuracy_ms: Option<u64>) -> Result<SignResponse> {
         let msg_nonce = nonce()?;
 
         // Mangle the message a bit
-        let msg_to_sign = raw_msg_to_signable(&now, msg, &msg_nonce);
+        let msg_to_sign = raw_msg_to_signable(RECEIPT_VERSION, &now, msg, &msg_nonce, accuracy_ms);
 
         let sg = self.sign_base64(&msg_to_sign);
         let kt = self.time_generated.clone();
         let pk = self.pub_key_base64.clone();
 
         Ok(SignResponse {
+            version: RECEIPT_VERSION,
             timestamp: now,
             key_time: kt,
             public_key: pk,
             message: msg.into(),
             signature: sg,
             nonce: msg_nonce,
+            accuracy_ms: accuracy_ms,
+        })
+    }
+
+    /// Sign a message, and wrap the receipt in a standard token format
+    pub fn sign_token(
+        &self,
+        now: ProveWhenTime,
+        msg: &str,
+        accuracy_ms: Option<u64>,
+        format: TokenFormat,
+    ) -> Result<ReceiptToken> {
+        let resp = self.sign(now, msg, accuracy_ms)?;
+        let claims = ReceiptClaims::from_response(&resp);
+
+        ReceiptToken::seal(format, &self.kid(), &claims, |m| self.sign_bytes(m))
+    }
+
+    /// Sign a reading of the clock for a client that wants to check its own
+    pub fn sign_time(&self, now: ProveWhenTime, client_nonce: &str, accuracy_ms: u64) -> Result<TimeResponse> {
+        check_client_nonce(client_nonce)?;
+
+        let sg = self.sign_base64(&time_to_signable(&now, accuracy_ms, client_nonce));
+
+        Ok(TimeResponse {
+            time: now,
+            accuracy_ms: accuracy_ms,
+            client_nonce: client_nonce.into(),
+            key_time: self.time_generated.clone(),
+            public_key: self.pub_key_base64.clone(),
+            signature: sg,
         })
     }
-}
\ No newline at end of file
+}
@@ -1,15 +1,62 @@
 use std::cmp::{Ord, Ordering};
-use rand::{thread_rng, sample};
 
 use datetime_utils::ProveWhenTime;
 
-use key_types::{PROOF_MESSAGES, SignResponse, SingleKeySet};
+use errors::*;
+use key_types::{key_id, raw_msg_to_signable, verify_base64, SignResponse, SingleKeySet};
+
+/// The only signature algorithm keys are issued for
+pub const KEY_ALGORITHM: &str = "Ed25519";
+
+/// A statement signed by a key about itself when it is generated. It
+/// proves possession of the key, and binds it to its period, to this
+/// server, and to the key before it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct KeyStatement {
+    pub key_time: ProveWhenTime,    // rfc3339 timestamp, start of the key's period
+    pub valid_until: ProveWhenTime, // rfc3339 timestamp, scheduled end of the period
+    pub algorithm: String,
+    pub previous_key_hash: Option<String>, // RFC 7638 thumbprint of the previous key
+    pub server_id: String,
+    pub signature: String, // base64 encoded Ed25519 signature
+}
+
+impl KeyStatement {
+    /// The string signed by the key. The server id goes last, so it may
+    /// contain any character without making the statement ambiguous
+    pub fn to_signable(&self, public_key: &str) -> String {
+        format!(
+            "provewhen.io key statement;public_key={};key_time={};valid_until={};\
+             algorithm={};previous_key_hash={};server_id={}",
+            public_key,
+            self.key_time.as_str(),
+            self.valid_until.as_str(),
+            self.algorithm,
+            self.previous_key_hash.as_ref().map_or("", |h| h.as_str()),
+            self.server_id
+        )
+    }
+}
+
+/// Keys logged before key statements existed are proven by a receipt
+/// for a random quote. They are still accepted, but never issued
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum KeyProof {
+    Statement(KeyStatement),
+    Legacy(SignResponse),
+}
 
 #[derive(Serialize, Deserialize, Eq, Clone)]
 pub struct TimedPublicKey {
     time: ProveWhenTime,
     public_key: String, // Base64 Public Key
-    proof: SignResponse
+    proof: KeyProof,
+
+    /// When an operator revoked the key. Only set on keys in responses,
+    /// the key list keeps revocations separately
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<ProveWhenTime>,
 }
 
 impl TimedPublicKey {
@@ -21,16 +68,97 @@ impl TimedPublicKey {
         &self.public_key
     }
 
-    pub fn from_single_keyset(key: &SingleKeySet) -> Self {
-        let sample = sample(&mut thread_rng(), PROOF_MESSAGES.iter(), 1);
-        let proof = key.sign(key.time_generated.clone(), &sample[0]).unwrap();
+    pub fn proof(&self) -> &KeyProof {
+        &self.proof
+    }
+
+    pub fn revoked_at(&self) -> Option<&ProveWhenTime> {
+        self.revoked_at.as_ref()
+    }
+
+    /// A copy marked as revoked at `time`, to respond with
+    pub fn marked_revoked(&self, time: &ProveWhenTime) -> Self {
+        TimedPublicKey {
+            revoked_at: Some(time.clone()),
+            ..self.clone()
+        }
+    }
+
+    pub fn kid(&self) -> Result<String> {
+        key_id(&self.public_key)
+    }
+
+    /// Publish `key`, with a statement chaining it to `previous`
+    pub fn new(key: &SingleKeySet, previous: Option<&TimedPublicKey>, server_id: &str) -> Self {
+        let mut statement = KeyStatement {
+            key_time: key.time_generated.clone(),
+            valid_until: key.time_generated.next_period(),
+            algorithm: KEY_ALGORITHM.into(),
+            previous_key_hash: previous.map(|p| p.kid().expect("logged keys are valid base64")),
+            server_id: server_id.into(),
+            signature: String::new(),
+        };
+
+        let signable = statement.to_signable(&key.pub_key_base64);
+        statement.signature = key.sign_base64(&signable);
 
         TimedPublicKey {
             time: key.time_generated.clone(),
             public_key: key.pub_key_base64.clone(),
-            proof: proof,
+            proof: KeyProof::Statement(statement),
+            revoked_at: None,
         }
     }
+
+    /// Check the proof was made by this key, for this key's time. Legacy
+    /// proofs only show possession of the key
+    pub fn verify_proof(&self) -> Result<()> {
+        match self.proof {
+            KeyProof::Statement(ref statement) => {
+                if statement.key_time != self.time {
+                    bail!(ErrorKind::KeyTimeMismatch);
+                }
+
+                verify_base64(
+                    &self.public_key,
+                    &statement.to_signable(&self.public_key),
+                    &statement.signature,
+                )
+            }
+            KeyProof::Legacy(ref receipt) => {
+                if receipt.public_key != self.public_key {
+                    bail!(ErrorKind::KeyMismatch);
+                }
+
+                verify_base64(
+                    &self.public_key,
+                    &raw_msg_to_signable(
+                        receipt.version,
+                        &receipt.timestamp,
+                        &receipt.message,
+                        &receipt.nonce,
+                        receipt.accuracy_ms,
+                    ),
+                    &receipt.signature,
+                )
+            }
+        }
+    }
+
+    /// Check that this key's statement names `previous` as its predecessor
+    pub fn verify_chain(&self, previous: &TimedPublicKey) -> Result<()> {
+        if let KeyProof::Statement(ref statement) = self.proof {
+            if statement.previous_key_hash != Some(previous.kid()?) {
+                bail!(
+                    "Key statement for {} does not follow the key from {}",
+                    self.time.as_str(),
+                    previous.time.as_str()
+                );
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Ord for TimedPublicKey {
@@ -49,4 +177,45 @@ impl PartialEq for TimedPublicKey {
     fn eq(&self, other: &Self) -> bool {
         self.time == other.time
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statement_chain() {
+        let first = TimedPublicKey::new(&SingleKeySet::new(), None, "test");
+        let second = TimedPublicKey::new(&SingleKeySet::new(), Some(&first), "test");
+
+        assert!(first.verify_proof().is_ok());
+        assert!(second.verify_proof().is_ok());
+        assert!(second.verify_chain(&first).is_ok());
+        assert!(first.verify_chain(&second).is_err());
+
+        // A proof can't be moved to another key
+        let mut moved = second.clone();
+        moved.proof = first.proof.clone();
+        assert!(moved.verify_proof().is_err());
+    }
+
+    #[test]
+    fn legacy_proof() {
+        let key = SingleKeySet::new();
+        let receipt = key.sign(key.time_generated.clone(), "The Time Machine", None).unwrap();
+
+        let json = format!(
+            r#"{{"time":"{}","public_key":"{}","proof":{}}}"#,
+            key.time_generated.as_str(),
+            key.pub_key_base64,
+            ::serde_json::to_string(&receipt).unwrap()
+        );
+
+        let legacy: TimedPublicKey = ::serde_json::from_str(&json).unwrap();
+        match legacy.proof {
+            KeyProof::Legacy(_) => {}
+            KeyProof::Statement(_) => panic!("legacy proof parsed as a statement"),
+        }
+        assert!(legacy.verify_proof().is_ok());
+    }
+}
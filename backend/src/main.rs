@@ -9,6 +9,9 @@ extern crate base64;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate serde_json;
+extern crate serde_cbor;
+extern crate rmp_serde;
 extern crate chrono;
 #[macro_use]
 extern crate error_chain;
@@ -17,55 +20,176 @@ extern crate rocket_contrib;
 extern crate rocket;
 #[macro_use]
 extern crate lazy_static;
-extern crate rand;
+extern crate ctrlc;
 
 mod pub_key_storage;
 mod api;
+mod api_keys;
+mod cli;
+mod clock;
 mod errors;
+mod events;
+mod file_utils;
+mod hooks;
 mod datetime_utils;
 mod key_types;
+mod metrics;
+mod roughtime;
+mod scheduler;
+mod settings;
+mod status;
+mod timesource;
 
+use std::env;
 use std::path::Path;
+use std::process;
 use std::thread;
-use std::time::Duration;
+use std::time::Duration as StdDuration;
 
 use mvdb::Mvdb;
 use pub_key_storage::KeyDB;
+use scheduler::SHUTDOWN;
+use settings::Settings;
+use status::{write_keystore, STATUS};
+
+/// Longest shutdown waits for requests being handled before the final
+/// keystore write
+const DRAIN_SECS: u64 = 10;
 
 fn main() {
-    let kpath = Path::new("keystore.json");
-    let keystore = Mvdb::from_file_or_default_pretty(&kpath).expect("Failed to load key database");
+    let settings = Settings::load(Path::new("provewhen.json")).expect("Failed to load settings");
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match cli::run(&args, &settings) {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let keystore = Mvdb::from_file_or_default_pretty(&settings.keystore)
+        .expect("Failed to load key database");
 
     // Generate a nonce to force random generator to be initialized
     key_types::nonce().expect("Failed to init random");
 
+    // Subscribe before defrosting, so hooks hear about gaps found on startup
+    let hook_events = if settings.hooks.targets.is_empty() {
+        None
+    } else {
+        Some(events::EVENTS.subscribe())
+    };
+
+    let master_seed = if settings.key_seed.enabled {
+        match key_types::unseal(&settings.key_seed) {
+            Ok(seed) => Some(seed),
+            Err(e) => {
+                println!("Error: failed to unseal the master seed: {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
     // render keypairs on load
     println!("Defrosting...");
-    keystore
-        .access_mut(|db: &mut KeyDB| db.defrost())
-        .unwrap()
-        .unwrap();
+    let defrosted = write_keystore(&keystore, |db: &mut KeyDB| {
+        if let Some(seed) = master_seed {
+            db.set_master_seed(seed);
+        }
+        db.set_regression_tolerance(chrono::Duration::milliseconds(
+            settings.time.regression_tolerance_ms as i64,
+        ));
+        db.set_configured_accuracy(chrono::Duration::milliseconds(settings.time.accuracy_ms as i64));
+        db.defrost(&settings.server_id)
+    });
+    if let Err(e) = defrosted.and_then(|rslt| rslt) {
+        println!("Error: failed to defrost the key database: {}", e);
+        process::exit(1);
+    }
     println!("Ready to eat!");
 
     let ks2 = keystore.clone();
 
-    let rkt_hdl = thread::spawn(|| { api::setup_rocket(keystore).launch(); });
+    if settings.admin.enabled {
+        let token = api::admin::load_or_create_token(&settings.admin.token_file)
+            .expect("Failed to load admin token");
+        let ks3 = keystore.clone();
+        let admin_settings = settings.clone();
+        thread::spawn(move || {
+            match api::admin::setup_admin_rocket(ks3, admin_settings, token) {
+                Ok(rocket) => { rocket.launch(); }
+                Err(e) => println!("Admin API not started: {}", e),
+            }
+        });
+    }
 
-    rotator(ks2);
+    // Background loops stop when a shutdown is requested, and are joined
+    // before the final keystore write
+    let mut workers = Vec::new();
 
-    rkt_hdl.join().unwrap();
-}
+    if settings.roughtime.enabled {
+        let ks3 = keystore.clone();
+        let rt_settings = settings.roughtime.clone();
+        workers.push(thread::spawn(move || if let Err(e) = roughtime::run(rt_settings, ks3) {
+            println!("Roughtime responder stopped: {}", e);
+        }));
+    }
+
+    if !settings.time_sources.servers.is_empty() {
+        let ts_settings = settings.time_sources.clone();
+        workers.push(thread::spawn(move || timesource::run(ts_settings)));
+    }
+
+    if let Some(hook_events) = hook_events {
+        let hook_settings = settings.hooks.clone();
+        workers.push(thread::spawn(move || if let Err(e) = hooks::run(hook_settings, hook_events) {
+            println!("ALERT: hooks stopped: {}", e);
+        }));
+    }
+
+    // SIGINT and SIGTERM stop the rotator. A second signal doesn't wait
+    // for the final flush
+    ctrlc::set_handler(|| if SHUTDOWN.request() {
+        println!("Shutting down now");
+        process::exit(1);
+    }).expect("Failed to install signal handler");
+
+    let rotation = settings.rotation.clone();
+    thread::spawn(|| { api::setup_rocket(keystore, settings).launch(); });
+
+    let rslt = scheduler::run(&ks2, &rotation);
 
-// --------
+    println!("Shutting down...");
+
+    // The rotator also stops on errors, take the other loops down with it
+    SHUTDOWN.request();
+
+    // Rocket 0.3 can't be stopped or joined, so its servers keep taking
+    // requests until the process exits. Refuse new receipts, and give the
+    // requests already being handled a bounded time to finish
+    STATUS.set_signing_paused(true);
+    let running = STATUS.drain_requests(StdDuration::from_secs(DRAIN_SECS));
+    if running > 0 {
+        println!("WARNING: {} requests still running after {}s", running, DRAIN_SECS);
+    }
+
+    for worker in workers {
+        let _ = worker.join();
+    }
 
-fn rotator(db: Mvdb<KeyDB>) {
-    loop {
-        db.access_mut(|db| {
-            let _ = db.get_current();
-            ()
-        }).expect("Keystore access failed!");
+    // Write the keystore once more. Requests arriving from here on can't
+    // sign, but may still read or rotate keys until the process exits
+    let flushed = write_keystore(&ks2, |_| ());
 
-        // TODO - add jitter
-        thread::sleep(Duration::from_secs(180));
+    match (rslt, flushed) {
+        (Ok(()), Ok(())) => println!("Keystore flushed, bye"),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
     }
 }
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use errors::*;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0,
+];
+
+pub struct Counter(AtomicUsize);
+
+impl Counter {
+    fn new() -> Self {
+        Counter(AtomicUsize::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters partitioned by the value of a single label
+pub struct LabeledCounter {
+    label: &'static str,
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl LabeledCounter {
+    fn new(label: &'static str) -> Self {
+        Self {
+            label: label,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, value: &str) {
+        *self.values.lock().unwrap().entry(value.into()).or_insert(0) += 1;
+    }
+}
+
+struct HistogramData {
+    buckets: Vec<u64>, // non-cumulative counts, one per LATENCY_BUCKETS entry
+    count: u64,
+    sum: f64,
+}
+
+pub struct Histogram(Mutex<HistogramData>);
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram(Mutex::new(HistogramData {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }))
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+        let mut data = self.0.lock().unwrap();
+        if let Some(n) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) {
+            data.buckets[n] += 1;
+        }
+        data.count += 1;
+        data.sum += secs;
+    }
+}
+
+pub struct Metrics {
+    pub sign_requests: LabeledCounter,
+    pub sign_failures: LabeledCounter,
+    pub verify_requests: Counter,
+    pub verify_failures: LabeledCounter,
+    pub keystore_write_seconds: Histogram,
+    pub keystore_lock_wait_seconds: Histogram,
+    pub rotations: Counter,
+    pub gap_keys: Counter,
+    pub clock_regressions: Counter,
+    pub time_source_failures: LabeledCounter,
+    pub hook_failures: LabeledCounter,
+}
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics {
+        sign_requests: LabeledCounter::new("endpoint"),
+        sign_failures: LabeledCounter::new("reason"),
+        verify_requests: Counter::new(),
+        verify_failures: LabeledCounter::new("reason"),
+        keystore_write_seconds: Histogram::new(),
+        keystore_lock_wait_seconds: Histogram::new(),
+        rotations: Counter::new(),
+        gap_keys: Counter::new(),
+        clock_regressions: Counter::new(),
+        time_source_failures: LabeledCounter::new("source"),
+        hook_failures: LabeledCounter::new("hook"),
+    };
+}
+
+impl Metrics {
+    /// Count a signing request made through `endpoint`, and its failure
+    pub fn observe_sign<T>(&self, endpoint: &str, rslt: Result<T>) -> Result<T> {
+        self.sign_requests.inc(endpoint);
+        if let Err(ref e) = rslt {
+            self.sign_failures.inc(e.reason());
+        }
+        rslt
+    }
+
+    /// Count a verification request, and its failure
+    pub fn observe_verify<T>(&self, rslt: Result<T>) -> Result<T> {
+        self.verify_requests.inc();
+        if let Err(ref e) = rslt {
+            self.verify_failures.inc(e.reason());
+        }
+        rslt
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        render_labeled(
+            &mut out,
+            "provewhen_sign_requests_total",
+            "Signing requests, by endpoint",
+            &self.sign_requests,
+        );
+        render_labeled(
+            &mut out,
+            "provewhen_sign_failures_total",
+            "Failed signing requests, by reason",
+            &self.sign_failures,
+        );
+        render_counter(
+            &mut out,
+            "provewhen_verify_requests_total",
+            "Receipt verification requests",
+            &self.verify_requests,
+        );
+        render_labeled(
+            &mut out,
+            "provewhen_verify_failures_total",
+            "Failed receipt verifications, by reason",
+            &self.verify_failures,
+        );
+        render_histogram(
+            &mut out,
+            "provewhen_keystore_write_seconds",
+            "Time taken to write the key database to disk",
+            &self.keystore_write_seconds,
+        );
+        render_histogram(
+            &mut out,
+            "provewhen_keystore_lock_wait_seconds",
+            "Time spent waiting for the key database lock",
+            &self.keystore_lock_wait_seconds,
+        );
+        render_counter(
+            &mut out,
+            "provewhen_rotations_total",
+            "Key rotations",
+            &self.rotations,
+        );
+        render_counter(
+            &mut out,
+            "provewhen_gap_keys_total",
+            "Keys back-filled for periods the server was not running",
+            &self.gap_keys,
+        );
+        render_counter(
+            &mut out,
+            "provewhen_clock_regressions_total",
+            "Times the clock was found behind the newest issued timestamp",
+            &self.clock_regressions,
+        );
+        render_labeled(
+            &mut out,
+            "provewhen_time_source_failures_total",
+            "Failed queries to time sources, by server",
+            &self.time_source_failures,
+        );
+        render_labeled(
+            &mut out,
+            "provewhen_hook_failures_total",
+            "Hook deliveries given up on after every attempt failed, by hook",
+            &self.hook_failures,
+        );
+
+        out
+    }
+}
+
+fn render_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    render_header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, counter.get());
+}
+
+fn render_labeled(out: &mut String, name: &str, help: &str, counter: &LabeledCounter) {
+    render_header(out, name, help, "counter");
+    for (value, count) in counter.values.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, counter.label, value, count);
+    }
+}
+
+fn render_histogram(out: &mut String, name: &str, help: &str, hist: &Histogram) {
+    render_header(out, name, help, "histogram");
+
+    let data = hist.0.lock().unwrap();
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(data.buckets.iter()) {
+        cumulative += *count;
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, data.count);
+    let _ = writeln!(out, "{}_sum {}", name, data.sum);
+    let _ = writeln!(out, "{}_count {}", name, data.count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_format() {
+        let counter = LabeledCounter::new("reason");
+        counter.inc("decode");
+        counter.inc("decode");
+
+        let hist = Histogram::new();
+        hist.observe(Duration::from_millis(2));
+        hist.observe(Duration::from_secs(10));
+
+        let mut out = String::new();
+        render_labeled(&mut out, "x_total", "help", &counter);
+        render_histogram(&mut out, "y_seconds", "help", &hist);
+
+        assert!(out.contains("# TYPE x_total counter\n"));
+        assert!(out.contains("x_total{reason=\"decode\"} 2\n"));
+        assert!(out.contains("y_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(out.contains("y_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(out.contains("y_seconds_bucket{le=\"5\"} 1\n"));
+        assert!(out.contains("y_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(out.contains("y_seconds_count 2\n"));
+    }
+}
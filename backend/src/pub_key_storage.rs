@@ -1,17 +1,93 @@
-use ring::signature;
-use base64;
-use untrusted;
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Arc;
 
+use chrono::Duration;
+
+use clock::{self, Clock};
 use datetime_utils::{DateTimeRange, ProveWhenTime};
 use errors::*;
 use key_types::*;
+use events::{KeyEvent, EVENTS};
+use metrics::METRICS;
+use settings::{DEFAULT_ACCURACY_MS, DEFAULT_REGRESSION_TOLERANCE_MS, DEFAULT_SERVER_ID};
+
+/// Events kept for resuming event streams, about six weeks of hourly
+/// rotations
+const RECENT_EVENTS: usize = 1000;
+
+/// A key withdrawn by an operator, e.g. after a suspected leak. Receipts
+/// signed with it no longer verify
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Revocation {
+    pub key_time: ProveWhenTime,
+    pub public_key: String,
+    pub reason: String,
+    pub revoked_at: ProveWhenTime,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct KeyDB {
     #[serde(skip)]
     current_key: SingleKeySet,
 
+    /// Key for the next period, generated ahead of time so it can be
+    /// announced before it is used. Like `current_key`, this is lost
+    /// on restart
+    #[serde(skip)]
+    next_key: Option<(SingleKeySet, TimedPublicKey)>,
+
+    /// Named in the statement of every new key. Set by `defrost`
+    #[serde(skip)]
+    server_id: String,
+
+    /// Decides when keys rotate and what time receipts carry
+    #[serde(skip, default = "clock::system")]
+    clock: Arc<Clock>,
+
     old_keys: Vec<TimedPublicKey>,
+
+    #[serde(default)]
+    revocations: Vec<Revocation>,
+
+    /// Every event published, newest last, so event streams can replay
+    /// gaps and revocations as well as rotations
+    #[serde(default)]
+    recent_events: VecDeque<KeyEvent>,
+
+    /// Newest timestamp issued in a receipt. Receipts are never stamped
+    /// earlier than this, so their order matches the order of signing
+    #[serde(default)]
+    high_water: Option<ProveWhenTime>,
+
+    #[serde(skip, default = "default_regression_tolerance")]
+    regression_tolerance: Duration,
+
+    /// Accuracy stated in receipts when the clock doesn't know its own
+    #[serde(skip, default = "default_accuracy")]
+    configured_accuracy: Duration,
+
+    /// Whether signing is currently refused because of the clock, so
+    /// the alert is raised once
+    #[serde(skip)]
+    clock_regressed: bool,
+
+    /// Set by `defrost` when the clock was behind the newest logged key.
+    /// The current key is logged, and signing starts, once it catches up
+    #[serde(skip)]
+    awaiting_clock: bool,
+
+    /// New keys are derived from this instead of generated at random
+    #[serde(skip)]
+    master_seed: Option<MasterSeed>,
+}
+
+fn default_regression_tolerance() -> Duration {
+    Duration::milliseconds(DEFAULT_REGRESSION_TOLERANCE_MS as i64)
+}
+
+fn default_accuracy() -> Duration {
+    Duration::milliseconds(DEFAULT_ACCURACY_MS as i64)
 }
 
 impl Default for KeyDB {
@@ -21,30 +97,315 @@ impl Default for KeyDB {
 }
 
 impl KeyDB {
+    /// An empty database. The current key is logged by `defrost`, once
+    /// the server id is known
     pub fn new() -> Self {
-        let mut new = Self {
+        Self::with_clock(clock::system())
+    }
+
+    pub fn with_clock(clock: Arc<Clock>) -> Self {
+        Self {
             old_keys: Vec::new(),
-            current_key: SingleKeySet::new(),
+            current_key: SingleKeySet::from_time(clock.now()),
+            next_key: None,
+            server_id: DEFAULT_SERVER_ID.into(),
+            clock: clock,
+            revocations: Vec::new(),
+            recent_events: VecDeque::new(),
+            high_water: None,
+            regression_tolerance: default_regression_tolerance(),
+            configured_accuracy: default_accuracy(),
+            clock_regressed: false,
+            awaiting_clock: false,
+            master_seed: None,
+        }
+    }
+
+    pub fn set_regression_tolerance(&mut self, tolerance: Duration) {
+        self.regression_tolerance = tolerance;
+    }
+
+    pub fn set_configured_accuracy(&mut self, accuracy: Duration) {
+        self.configured_accuracy = accuracy;
+    }
+
+    pub fn high_water(&self) -> Option<&ProveWhenTime> {
+        self.high_water.as_ref()
+    }
+
+    /// How far the clock is behind the newest issued timestamp, if that
+    /// is more than the tolerance
+    pub fn clock_regression(&self) -> Option<Duration> {
+        let now = self.clock.now();
+
+        // Until the current key is logged, any step back counts
+        if self.awaiting_clock {
+            if let Some(latest) = self.old_keys.last() {
+                let behind = latest.time().inner().signed_duration_since(*now.inner());
+                if behind > Duration::zero() {
+                    return Some(behind);
+                }
+            }
+        }
+
+        self.high_water.as_ref().and_then(|hw| {
+            let behind = hw.inner().signed_duration_since(*now.inner());
+            if behind > self.regression_tolerance {
+                Some(behind)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Rotate if due, and pick the timestamp for a new receipt
+    fn signing_time(&mut self) -> Result<ProveWhenTime> {
+        if let Some(behind) = self.clock_regression() {
+            if !self.clock_regressed {
+                self.clock_regressed = true;
+                METRICS.clock_regressions.inc();
+                println!(
+                    "ALERT: clock is {}ms behind the newest issued timestamp, signing is stopped",
+                    behind.num_milliseconds()
+                );
+            }
+
+            bail!(ErrorKind::ClockRegression(behind.num_milliseconds()));
+        }
+
+        if self.clock_regressed {
+            self.clock_regressed = false;
+            println!("Clock caught up with the newest issued timestamp, signing resumed");
+        }
+
+        self.get_current();
+
+        let now = self.clock.now();
+        let stamp = match self.high_water {
+            Some(ref hw) if *hw > now => hw.clone(),
+            _ => now,
         };
+        self.high_water = Some(stamp.clone());
+
+        Ok(stamp)
+    }
+
+    /// Derive new keys from `seed`. Like `set_clock`, takes effect for
+    /// the current key at the next `defrost`
+    pub fn set_master_seed(&mut self, seed: MasterSeed) {
+        self.master_seed = Some(seed);
+    }
+
+    /// A new key for `time`. Derived keys are numbered by how many keys
+    /// were logged in their period before them
+    fn make_key(&self, time: ProveWhenTime) -> SingleKeySet {
+        match self.master_seed {
+            Some(ref seed) => {
+                let generation = generation(&self.old_keys, &time);
+                seed.derive(time, generation)
+            }
+            None => SingleKeySet::from_time(time),
+        }
+    }
+
+    /// For each logged key, whether `seed` regenerates it. Keys from
+    /// before the seed was used are expected not to match
+    pub fn check_master_seed(&self, seed: &MasterSeed) -> Vec<(TimedPublicKey, bool)> {
+        self.old_keys
+            .iter()
+            .cloned()
+            .zip(seed.check(&self.old_keys))
+            .collect()
+    }
 
-        new.log_current_key();
+    /// Replace the clock, e.g. after loading from disk. Takes effect for
+    /// the current key at the next `defrost`
+    pub fn set_clock(&mut self, clock: Arc<Clock>) {
+        self.clock = clock;
+    }
 
-        new
+    /// The time according to this database's clock
+    pub fn now(&self) -> ProveWhenTime {
+        self.clock.now()
     }
 
     fn rotate(&mut self, new: SingleKeySet) {
         self.current_key = new;
         self.log_current_key();
+        METRICS.rotations.inc();
+
+        if let Some(key) = self.old_keys.last().cloned() {
+            self.publish(KeyEvent::Rotation { key: key });
+        }
+    }
+
+    /// Record `event` for replay, and send it to live subscribers
+    fn publish(&mut self, event: KeyEvent) {
+        if self.recent_events.len() >= RECENT_EVENTS {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back(event.clone());
+
+        EVENTS.publish(event);
     }
 
     pub fn get_current(&mut self) -> &SingleKeySet {
+        if self.awaiting_clock && !self.catch_up() {
+            return &self.current_key;
+        }
+
         if self.time_to_switch() {
-            self.rotate(SingleKeySet::new());
+            let now = self.clock.now();
+            let period = now.floored();
+
+            // Use the announced key, unless it was prepared for a period
+            // that has already passed
+            let new = match self.next_key.take() {
+                Some((key, _)) if key.time_generated == period => key,
+                _ => self.make_key(now),
+            };
+
+            self.rotate(new);
         }
 
         &self.current_key
     }
 
+    /// Replace the signing key immediately, instead of at the end of its
+    /// period. The announced next key is discarded as well, in case it
+    /// was exposed along with the current one. With a `revoke_reason`,
+    /// the old key is also revoked
+    pub fn force_rotate(&mut self, revoke_reason: Option<String>) -> Result<TimedPublicKey> {
+        let old = self.current_public_key()?;
+
+        self.next_key = None;
+        let new = self.make_key(self.clock.now());
+        self.rotate(new);
+
+        if let Some(reason) = revoke_reason {
+            self.revocations.push(Revocation {
+                key_time: old.time().clone(),
+                public_key: old.public_key().to_string(),
+                reason: reason.clone(),
+                revoked_at: self.clock.now(),
+            });
+
+            self.publish(KeyEvent::Revocation {
+                key_time: old.time().clone(),
+                public_key: old.public_key().to_string(),
+                reason: reason,
+            });
+        }
+
+        self.current_public_key()
+    }
+
+    pub fn revocations(&self) -> &[Revocation] {
+        &self.revocations
+    }
+
+    pub fn is_revoked(&self, pub_key_base64: &str) -> bool {
+        self.revocations.iter().any(|r| r.public_key == pub_key_base64)
+    }
+
+    /// `key` as served to clients, marked if it was revoked
+    pub fn key_response(&self, key: &TimedPublicKey) -> TimedPublicKey {
+        match self.revocations.iter().find(|r| r.public_key == key.public_key()) {
+            Some(revocation) => key.marked_revoked(&revocation.revoked_at),
+            None => key.clone(),
+        }
+    }
+
+    /// Accuracy stated with signed times: the clock's measured accuracy
+    /// if it has one, otherwise the configured one. Rounded up to whole
+    /// milliseconds
+    pub fn accuracy_ms(&self) -> u64 {
+        let accuracy = self.clock.accuracy().unwrap_or(self.configured_accuracy);
+
+        let ms = accuracy.num_milliseconds();
+        let ms = if accuracy > Duration::milliseconds(ms) { ms + 1 } else { ms };
+        cmp::max(ms, 0) as u64
+    }
+
+    /// Sign `msg` with the current key, rotating first if its period is over
+    pub fn sign(&mut self, msg: &str) -> Result<SignResponse> {
+        let now = self.signing_time()?;
+        let accuracy_ms = self.accuracy_ms();
+        self.current_key.sign(now, msg, Some(accuracy_ms))
+    }
+
+    pub fn sign_token(&mut self, msg: &str, format: TokenFormat) -> Result<ReceiptToken> {
+        let now = self.signing_time()?;
+        let accuracy_ms = self.accuracy_ms();
+        self.current_key.sign_token(now, msg, Some(accuracy_ms), format)
+    }
+
+    pub fn sign_time(&mut self, client_nonce: &str) -> Result<TimeResponse> {
+        let now = self.signing_time()?;
+        let accuracy_ms = self.accuracy_ms();
+        self.current_key.sign_time(now, client_nonce, accuracy_ms)
+    }
+
+    /// The signing key, without rotating it. Callers that can't take the
+    /// write lock check `needs_rotation` first
+    pub fn current_key(&self) -> &SingleKeySet {
+        &self.current_key
+    }
+
+    /// Generate the key for the next period, if it doesn't exist yet
+    pub fn prepare_next(&mut self) {
+        if self.awaiting_clock {
+            return;
+        }
+
+        let period = self.current_key.time_generated.next_period();
+
+        let ready = match self.next_key {
+            Some((ref key, _)) => key.time_generated == period,
+            None => false,
+        };
+
+        if !ready {
+            let key = self.make_key(period);
+            let public = TimedPublicKey::new(&key, self.old_keys.last(), &self.server_id);
+            self.next_key = Some((key, public));
+        }
+    }
+
+    /// The public half of the key currently used for signing
+    pub fn current_public_key(&self) -> Result<TimedPublicKey> {
+        match self.old_keys
+            .iter()
+            .rev()
+            .find(|k| *k.public_key() == self.current_key.pub_key_base64)
+        {
+            Some(k) => Ok(k.clone()),
+            None => bail!("Current key has not been logged"),
+        }
+    }
+
+    /// The public half of the announced key for the next period
+    pub fn next_public_key(&self) -> Option<&TimedPublicKey> {
+        self.next_key.as_ref().map(|&(_, ref public)| public)
+    }
+
+    /// Whether the current key belongs to a period that has passed
+    pub fn needs_rotation(&self) -> bool {
+        self.time_to_switch()
+    }
+
+    pub fn current_key_time(&self) -> &ProveWhenTime {
+        &self.current_key.time_generated
+    }
+
+    pub fn latest_key_time(&self) -> Option<&ProveWhenTime> {
+        self.old_keys.last().map(|k| k.time())
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.old_keys.len()
+    }
+
     pub fn range(&self, start: &ProveWhenTime, end: &ProveWhenTime) -> Result<&[TimedPublicKey]> {
         if end < start {
             bail!("malformed request")
@@ -69,35 +430,160 @@ impl KeyDB {
         Ok(&self.old_keys[lbound..rbound])
     }
 
+    /// All keys that were in use at some point between `start` and `end`,
+    /// each paired with the time it stopped being used
+    pub fn window(
+        &self,
+        start: &ProveWhenTime,
+        end: &ProveWhenTime,
+    ) -> Result<Vec<(TimedPublicKey, ProveWhenTime)>> {
+        if end < start {
+            bail!("malformed request")
+        }
+
+        // Include the key that was already active at `start`
+        let lbound = match self.old_keys
+            .binary_search_by_key(start.inner(), |ref i| i.time().inner().clone())
+        {
+            Ok(n) => n,
+            Err(0) => 0,
+            Err(n) => n - 1,
+        };
+
+        // Include a key generated exactly at `end`
+        let rbound = match self.old_keys
+            .binary_search_by_key(end.inner(), |ref i| i.time().inner().clone())
+        {
+            Ok(n) => n + 1,
+            Err(n) => n,
+        };
+
+        if rbound <= lbound {
+            return Ok(Vec::new());
+        }
+
+        Ok(
+            (lbound..rbound)
+                .map(|n| (self.old_keys[n].clone(), self.valid_until(n)))
+                .collect(),
+        )
+    }
+
+    /// All keys generated strictly after `since`, oldest first
+    pub fn keys_after(&self, since: &ProveWhenTime) -> Vec<TimedPublicKey> {
+        self.old_keys
+            .iter()
+            .filter(|k| k.time() > since)
+            .cloned()
+            .collect()
+    }
+
+    /// Events to replay to a stream resuming from `since`, in the order
+    /// they were published. Rotations from before the oldest recorded
+    /// rotation come from the key list
+    pub fn events_after(&self, since: &ProveWhenTime) -> Vec<KeyEvent> {
+        let recorded_from = self.recent_events
+            .iter()
+            .filter_map(|e| match *e {
+                KeyEvent::Rotation { ref key } => Some(key.time().clone()),
+                _ => None,
+            })
+            .next();
+
+        let mut events = self.keys_after(since)
+            .into_iter()
+            .filter(|k| recorded_from.as_ref().map_or(true, |t| k.time() < t))
+            .map(|key| KeyEvent::Rotation { key: key })
+            .collect::<Vec<_>>();
+
+        // A revocation is about a key older than the rotation before it,
+        // so everything from the first newer event on is replayed
+        let start = self.recent_events
+            .iter()
+            .position(|e| e.time() > since)
+            .unwrap_or(self.recent_events.len());
+        events.extend(self.recent_events.iter().skip(start).cloned());
+
+        events
+    }
+
+    /// The end of the validity period of `old_keys[n]`
+    fn valid_until(&self, n: usize) -> ProveWhenTime {
+        match self.old_keys.get(n + 1) {
+            Some(next) => next.time().clone(),
+            None => self.old_keys[n].time().next_period(),
+        }
+    }
+
     pub fn verify(
         &self,
-        message: &SignResponse
+        message: &SignResponse,
+        mode: VerifyMode,
     ) -> Result<()> {
         // Does a key exist for that time?
-        let pk_base64 = self.get_public_key_by_time(&message.timestamp)?.public_key().to_string();
+        let n = self.index_by_time(&message.timestamp)?;
+        let key = &self.old_keys[n];
+        let pk_base64 = key.public_key().to_string();
 
         // Does the alleged key match ours?
         if pk_base64 != message.public_key {
-            bail!("Key mismatch!");
+            bail!(ErrorKind::KeyMismatch);
+        }
+
+        if mode == VerifyMode::Strict {
+            if *key.time() != message.key_time {
+                bail!(ErrorKind::KeyTimeMismatch);
+            }
+
+            // The lookup guarantees the timestamp isn't before the key,
+            // but the newest key is only valid until the end of its period
+            if message.timestamp >= self.valid_until(n) {
+                bail!(ErrorKind::OutsideValidity);
+            }
+
+            check_nonce(&message.nonce)?;
         }
 
-        // Now decode sig and pk
-        let pk = base64::decode(&pk_base64).chain_err(|| "failed to decode")?;
-        let alleged_sig = base64::decode(&message.signature)
-            .chain_err(|| "failed to decode")?;
+        if self.is_revoked(&pk_base64) {
+            bail!(ErrorKind::KeyRevoked);
+        }
+
+        // Version 1 signatures don't cover an accuracy, so one can't be
+        // vouched for
+        if message.version < 2 && message.accuracy_ms.is_some() {
+            bail!(ErrorKind::SignatureMismatch);
+        }
 
-        signature::verify(
-            &signature::ED25519,
-            untrusted::Input::from(&pk),
-            untrusted::Input::from(raw_msg_to_signable(&message.timestamp, &message.message, &message.nonce).as_bytes()),
-            untrusted::Input::from(&alleged_sig),
-        ).chain_err(|| "Signature mismatch!")
+        verify_base64(
+            &pk_base64,
+            &raw_msg_to_signable(
+                message.version,
+                &message.timestamp,
+                &message.message,
+                &message.nonce,
+                message.accuracy_ms,
+            ),
+            &message.signature,
+        )
     }
 
     pub fn get_public_key_by_time(&self, rtime: &ProveWhenTime) -> Result<TimedPublicKey> {
-        if ProveWhenTime::now() < *rtime {
+        Ok(self.key_response(&self.old_keys[self.index_by_time(rtime)?]))
+    }
+
+    /// Index in `old_keys` of the key that was in use at `rtime`
+    fn index_by_time(&self, rtime: &ProveWhenTime) -> Result<usize> {
+        // Receipts are stamped with the high water mark while it is ahead
+        // of the clock, and are valid right away
+        let now = self.clock.now();
+        let latest_issued = match self.high_water {
+            Some(ref hw) if *hw > now => hw,
+            _ => &now,
+        };
+
+        if *latest_issued < *rtime {
             // Time is in the future
-            bail!("Cannot provide future keys");
+            bail!(ErrorKind::FutureKey);
         }
 
         /////////////////////////////////////////////////
@@ -107,10 +593,10 @@ impl KeyDB {
             .binary_search_by_key(rtime.inner(), |ref i| i.time().inner().clone())
         {
             // An exact match was found for the key
-            Ok(n) => Ok(self.old_keys[n].clone()),
+            Ok(n) => Ok(n),
 
             // The search fell off the left end of the list
-            Err(0) => bail!("Time is before recorded history"),
+            Err(0) => bail!(ErrorKind::BeforeHistory),
 
             // The search fell WAY off the right end of the list,
             // probably not possible (unless a bug in binary_search)
@@ -119,128 +605,640 @@ impl KeyDB {
             // The search didn't find an exact match, so we can take the
             // item "to the left", which is the "price is right" match:
             // closest without going over, including if n == old_keys.len()
-            Err(n) => Ok(self.old_keys[n - 1].clone()),
+            Err(n) => Ok(n - 1),
         }
 
 
     }
 
     fn log_current_key(&mut self) {
-        self.old_keys.push(TimedPublicKey::from_single_keyset(&self.current_key));
+        let public = TimedPublicKey::new(&self.current_key, self.old_keys.last(), &self.server_id);
+        self.old_keys.push(public);
+    }
+
+    /// Check every logged key's proof, and that statements chain up
+    fn verify_proofs(&self) -> Result<()> {
+        for (n, key) in self.old_keys.iter().enumerate() {
+            key.verify_proof()
+                .chain_err(|| format!("Invalid proof for the key from {}", key.time().as_str()))?;
+
+            if n > 0 {
+                key.verify_chain(&self.old_keys[n - 1])?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Should be called some time between deserialization and use
-    pub fn defrost(&mut self) -> Result<()> {
+    pub fn defrost(&mut self, server_id: &str) -> Result<()> {
+        self.server_id = server_id.into();
+
         // Ensure the key storage is sorted
         self.old_keys.sort();
+        self.verify_proofs()?;
 
-        let latest = match self.old_keys.last().cloned() {
-            Some(k) => {
-                // Make sure the current key exists in the old list
-                if *k.public_key() != self.current_key.pub_key_base64 {
-                    self.log_current_key();
-                }
+        let logged = self.old_keys
+            .iter()
+            .any(|k| *k.public_key() == self.current_key.pub_key_base64);
+        if logged {
+            return Ok(());
+        }
 
-                k
-            },
-            None => {
-                // All code after this is processing old keys, nothing
-                // more to do
-                self.log_current_key();
-                return Ok(())
-            },
-        };
+        // The current key isn't stored, so the one made on load is new.
+        // Remake it by our clock, which may have been set since, once the
+        // keys before it are logged
+        let now = self.clock.now();
 
+        if let Some(latest) = self.old_keys.last().cloned() {
+            // Logging a key older than the newest one would break the
+            // order of the key list, so start without a usable key and
+            // wait for the clock to catch up
+            if *latest.time() > now {
+                let behind = latest.time().inner().signed_duration_since(*now.inner());
+                println!(
+                    "ALERT: clock is {}ms behind the newest logged key, signing waits for it to catch up",
+                    behind.num_milliseconds()
+                );
 
-        // Fill in between last run and current
-        let filler = DateTimeRange::new(
-            latest.time(),
-            &self.current_key.time_generated,
-        ).map(|time| SingleKeySet::from_time(time))
-            .collect::<Vec<SingleKeySet>>();
+                self.awaiting_clock = true;
+                return Ok(());
+            }
 
-        // Insert all the old keys
-        for key_pair in filler {
-            self.old_keys
-                .push(TimedPublicKey::from_single_keyset(&key_pair));
+            self.fill_gap(&latest, &now);
         }
 
+        self.start_current_key(now);
+
         Ok(())
     }
 
+    /// Finish what `defrost` started, if the clock has passed the newest
+    /// logged key. Returns whether the current key is logged
+    fn catch_up(&mut self) -> bool {
+        let now = self.clock.now();
+        let latest = match self.old_keys.last().cloned() {
+            Some(k) => k,
+            None => return false,
+        };
+
+        if *latest.time() > now {
+            return false;
+        }
+
+        self.awaiting_clock = false;
+        self.fill_gap(&latest, &now);
+        self.start_current_key(now);
+
+        println!("Clock passed the newest logged key, signing starts");
+        true
+    }
+
+    /// Make, log and announce the key a (re)started server signs with
+    fn start_current_key(&mut self, now: ProveWhenTime) {
+        self.current_key = self.make_key(now);
+        self.log_current_key();
+
+        if let Some(key) = self.old_keys.last().cloned() {
+            self.publish(KeyEvent::Rotation { key: key });
+        }
+    }
+
+    /// Log a key for each period between `latest` and `until`, which the
+    /// server wasn't running for
+    fn fill_gap(&mut self, latest: &TimedPublicKey, until: &ProveWhenTime) {
+        let times = DateTimeRange::new(latest.time(), until).collect::<Vec<_>>();
+
+        METRICS.gap_keys.inc_by(times.len());
+
+        if !times.is_empty() {
+            self.publish(KeyEvent::Gap {
+                from: latest.time().clone(),
+                to: until.clone(),
+                keys: times.len(),
+            });
+        }
+
+        // Log the filler keys before the current key, so the list stays
+        // sorted and each statement names the key before it
+        for time in times {
+            let key = self.make_key(time);
+            let public = TimedPublicKey::new(&key, self.old_keys.last(), &self.server_id);
+            self.old_keys.push(public.clone());
+            self.publish(KeyEvent::Rotation { key: public });
+        }
+    }
+
     fn time_to_switch(&self) -> bool {
-        self.current_key.time_generated < ProveWhenTime::now().floored()
+        self.current_key.time_generated < self.clock.now().floored()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use chrono::Duration;
+    use serde_json;
+
+    use clock::{at, ManualClock};
     use super::*;
+
+    /// 15 minutes into a period
+    const START: &str = "2018-03-01T10:15:00+00:00";
+
+    fn test_db() -> (KeyDB, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::at(START));
+        let mut kdb = KeyDB::with_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+        (kdb, clock)
+    }
+
     #[test]
     fn sign_verify() {
-        let kdb = KeyDB::new();
-        let now = ProveWhenTime::now();
+        let (mut kdb, _) = test_db();
+
+        let signed = kdb.sign("This is a test of the KeyDB").unwrap();
+
+        assert!(signed.timestamp == at(START));
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_ok());
+    }
+
+    #[test]
+    fn accuracy_is_signed() {
+        let (mut kdb, clock) = test_db();
+
+        let signed = kdb.sign("This is a test of the KeyDB").unwrap();
+        assert_eq!(signed.accuracy_ms, Some(DEFAULT_ACCURACY_MS));
+
+        let (not_before, not_after) = signed.interval().unwrap();
+        assert!(not_before == at("2018-03-01T10:14:59+00:00"));
+        assert!(not_after == at("2018-03-01T10:15:01+00:00"));
+
+        clock.set_accuracy(Some(Duration::microseconds(12_300)));
 
-        let signed = kdb.current_key.sign(now, "This is a test of the KeyDB").unwrap();
+        let mut signed = kdb.sign("This is a test of the KeyDB").unwrap();
+        assert_eq!(signed.accuracy_ms, Some(13));
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_ok());
 
-        assert!(kdb.verify(&signed).is_ok());
+        signed.accuracy_ms = Some(1);
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_err());
+        signed.accuracy_ms = None;
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_err());
+    }
+
+    #[test]
+    fn version_is_signed() {
+        let (mut kdb, _) = test_db();
+        let signed = kdb.sign("This is a test of the KeyDB").unwrap();
+
+        // Dropping the version doesn't get a receipt lenient checks
+        let mut legacy = signed.clone();
+        legacy.version = 1;
+        assert!(kdb.verify(&legacy, VerifyMode::Lenient).is_err());
+        legacy.accuracy_ms = None;
+        assert!(kdb.verify(&legacy, VerifyMode::Lenient).is_err());
+
+        // Nor can the fields be split differently
+        let mut resplit = signed.clone();
+        resplit.message = format!("{};{}", signed.message, signed.nonce);
+        resplit.nonce = format!("accuracy_ms={}", signed.accuracy_ms.unwrap());
+        assert!(kdb.verify(&resplit, VerifyMode::Lenient).is_err());
     }
 
     #[test]
     fn sign_verify_bad_time() {
-        let kdb = KeyDB::new();
-        let now = ProveWhenTime::now();
+        let (mut kdb, clock) = test_db();
 
-        let mut signed = kdb.current_key.sign(now, "This is a test of the KeyDB").unwrap();
+        let mut signed = kdb.sign("This is a test of the KeyDB").unwrap();
 
-        signed.timestamp = ProveWhenTime::now();
+        clock.advance(Duration::seconds(1));
+        signed.timestamp = kdb.now();
 
-        assert!(kdb.verify(&signed).is_err());
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_err());
     }
 
     #[test]
     fn sign_verify_bad_message() {
-        let kdb = KeyDB::new();
-        let now = ProveWhenTime::now();
+        let (mut kdb, _) = test_db();
 
-        let mut signed = kdb.current_key.sign(now, "This is a test of the KeyDB").unwrap();
+        let mut signed = kdb.sign("This is a test of the KeyDB").unwrap();
 
         signed.message = "This message has changed".into();
 
-        assert!(kdb.verify(&signed).is_err());
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_err());
     }
 
     #[test]
     fn sign_verify_bad_nonce() {
-        let kdb = KeyDB::new();
-        let now = ProveWhenTime::now();
+        let (mut kdb, _) = test_db();
 
-        let mut signed = kdb.current_key.sign(now, "This is a test of the KeyDB").unwrap();
+        let mut signed = kdb.sign("This is a test of the KeyDB").unwrap();
 
         signed.nonce = nonce().unwrap();
 
-        assert!(kdb.verify(&signed).is_err());
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_err());
+    }
+
+    #[test]
+    fn rotation_at_period_boundary() {
+        let (mut kdb, clock) = test_db();
+        let first = kdb.current_public_key().unwrap();
+
+        // Still the same period
+        clock.set(*at("2018-03-01T10:59:59+00:00").inner());
+        assert!(!kdb.needs_rotation());
+        let before = kdb.sign("before").unwrap();
+        assert_eq!(before.public_key, first.public_key());
+
+        // The first signature in a new period rotates the key
+        clock.set(*at("2018-03-01T11:00:00+00:00").inner());
+        assert!(kdb.needs_rotation());
+        let after = kdb.sign("after").unwrap();
+        assert!(after.public_key != first.public_key());
+        assert!(after.key_time == at("2018-03-01T11:00:00+00:00"));
+        assert_eq!(kdb.key_count(), 2);
+
+        // Receipts from both sides of the boundary verify strictly
+        assert!(kdb.verify(&before, VerifyMode::Strict).is_ok());
+        assert!(kdb.verify(&after, VerifyMode::Strict).is_ok());
     }
 
     #[test]
     fn sign_verify_old_key() {
-        let mut kdb = KeyDB::new();
+        let (mut kdb, clock) = test_db();
 
-        // Fill in some old keys
+        // Run through some periods
         for _ in 0..50 {
-            kdb.rotate(SingleKeySet::new());
+            clock.advance(Duration::hours(1));
+            kdb.sign("filler").unwrap();
         }
 
         // Sign a message
-        let now = ProveWhenTime::now();
-        let signed = kdb.current_key.sign(now, "This is a test of the KeyDB").unwrap();
+        let signed = kdb.sign("This is a test of the KeyDB").unwrap();
 
-        // Fill in some more old keys
+        // Run through some more periods
         for _ in 0..50 {
-            kdb.rotate(SingleKeySet::new());
+            clock.advance(Duration::hours(1));
+            kdb.sign("filler").unwrap();
         }
 
         // Check the message
-        assert!(kdb.verify(&signed).is_ok());
+        assert_eq!(kdb.key_count(), 101);
+        assert!(kdb.verify(&signed, VerifyMode::Strict).is_ok());
+    }
+
+    #[test]
+    fn future_and_past_keys() {
+        let (kdb, _) = test_db();
+
+        match kdb.get_public_key_by_time(&at("2018-03-01T10:15:01+00:00")) {
+            Err(Error(ErrorKind::FutureKey, _)) => {}
+            _ => panic!("served a key for the future"),
+        }
+
+        match kdb.get_public_key_by_time(&at("2018-03-01T10:14:59+00:00")) {
+            Err(Error(ErrorKind::BeforeHistory, _)) => {}
+            _ => panic!("served a key from before the first one"),
+        }
+
+        assert!(kdb.get_public_key_by_time(&at(START)).is_ok());
+    }
+
+    #[test]
+    fn outside_validity() {
+        let (kdb, clock) = test_db();
+
+        // The rotator is late, and the key is used past its period
+        clock.set(*at("2018-03-01T12:30:00+00:00").inner());
+        let late = kdb.current_key.sign(kdb.now(), "late", None).unwrap();
+
+        assert!(kdb.verify(&late, VerifyMode::Lenient).is_ok());
+        match kdb.verify(&late, VerifyMode::Strict) {
+            Err(Error(ErrorKind::OutsideValidity, _)) => {}
+            _ => panic!("receipt after the key's period passed strict verification"),
+        }
+    }
+
+    #[test]
+    fn gap_filled_on_restart() {
+        let (kdb, clock) = test_db();
+        let stored = serde_json::to_string(&kdb).unwrap();
+
+        // Down for a few hours
+        clock.set(*at("2018-03-01T14:40:00+00:00").inner());
+
+        let mut kdb: KeyDB = serde_json::from_str(&stored).unwrap();
+        kdb.set_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+
+        // The first key, a back-filled key for each hour since, and the
+        // new current key
+        let times = kdb.old_keys.iter().map(|k| k.time().as_str().to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            times,
+            vec![
+                "2018-03-01T10:15:00+00:00",
+                "2018-03-01T11:00:00+00:00",
+                "2018-03-01T12:00:00+00:00",
+                "2018-03-01T13:00:00+00:00",
+                "2018-03-01T14:00:00+00:00",
+                "2018-03-01T14:40:00+00:00",
+            ]
+        );
+        assert!(kdb.current_key_time() == &at("2018-03-01T14:40:00+00:00"));
+
+        // Sorted, and each statement names the key before it
+        assert!(kdb.verify_proofs().is_ok());
+    }
+
+    #[test]
+    fn window_validity() {
+        let (mut kdb, clock) = test_db();
+
+        for _ in 0..5 {
+            clock.advance(Duration::hours(1));
+            kdb.get_current();
+        }
+
+        let start = kdb.old_keys[0].time().clone();
+        let window = kdb.window(&start, &kdb.now()).unwrap();
+
+        assert_eq!(window.len(), 6);
+
+        // Each key is valid until the next one takes over
+        for pair in window.windows(2) {
+            assert!(pair[0].1 == *pair[1].0.time());
+        }
+
+        // The newest key is valid until the next rotation
+        let &(ref last, ref until) = window.last().unwrap();
+        assert!(*until == last.time().next_period());
+    }
+
+    #[test]
+    fn announced_key_used_at_boundary() {
+        let (mut kdb, clock) = test_db();
+        assert!(kdb.next_public_key().is_none());
+
+        clock.set(*at("2018-03-01T10:50:00+00:00").inner());
+        kdb.prepare_next();
+        let announced = kdb.next_public_key().unwrap().clone();
+        assert!(*announced.time() == at("2018-03-01T11:00:00+00:00"));
+
+        // Preparing again keeps the announced key
+        kdb.prepare_next();
+        assert_eq!(kdb.next_public_key().unwrap().public_key(), announced.public_key());
+
+        clock.set(*at("2018-03-01T11:00:30+00:00").inner());
+        let signed = kdb.sign("announced").unwrap();
+        assert_eq!(signed.public_key, announced.public_key());
+        assert!(kdb.next_public_key().is_none());
+    }
+
+    #[test]
+    fn stale_announcement_discarded() {
+        let (mut kdb, clock) = test_db();
+        kdb.prepare_next();
+        let announced = kdb.next_public_key().unwrap().clone();
+
+        // Nothing signed during the announced key's period
+        clock.set(*at("2018-03-01T12:05:00+00:00").inner());
+        let signed = kdb.sign("later").unwrap();
+
+        assert!(signed.public_key != announced.public_key());
+        assert!(signed.key_time == at("2018-03-01T12:05:00+00:00"));
+    }
+
+    #[test]
+    fn force_rotate_revokes() {
+        let (mut kdb, clock) = test_db();
+        kdb.prepare_next();
+
+        let before = kdb.sign("before").unwrap();
+
+        clock.advance(Duration::minutes(5));
+        let new = kdb.force_rotate(Some("suspected leak".into())).unwrap();
+
+        assert!(new.public_key() != before.public_key);
+        assert!(*new.time() == at("2018-03-01T10:20:00+00:00"));
+        assert!(kdb.next_public_key().is_none());
+        match kdb.verify(&before, VerifyMode::Strict) {
+            Err(Error(ErrorKind::KeyRevoked, _)) => {}
+            _ => panic!("receipt from a revoked key verified"),
+        }
+
+        let after = kdb.sign("after").unwrap();
+        assert!(kdb.verify(&after, VerifyMode::Strict).is_ok());
+
+        // Key lookups say so
+        let looked_up = kdb.get_public_key_by_time(&before.timestamp).unwrap();
+        assert!(looked_up.revoked_at() == Some(&at("2018-03-01T10:20:00+00:00")));
+        assert!(kdb.get_public_key_by_time(&after.timestamp).unwrap().revoked_at().is_none());
+    }
+
+    #[test]
+    fn strict_checks_key_time() {
+        let (mut kdb, _) = test_db();
+
+        // `key_time` isn't covered by the signature
+        let mut signed = kdb.sign("key time").unwrap();
+        signed.key_time = at("2018-03-01T10:00:00+00:00");
+
+        assert!(kdb.verify(&signed, VerifyMode::Lenient).is_ok());
+        match kdb.verify(&signed, VerifyMode::Strict) {
+            Err(Error(ErrorKind::KeyTimeMismatch, _)) => {}
+            _ => panic!("mismatched key_time passed strict verification"),
+        }
+    }
+
+    #[test]
+    fn clock_regression_stops_signing() {
+        let (mut kdb, clock) = test_db();
+        let first = kdb.sign("first").unwrap();
+
+        // A small step back is absorbed, the receipt keeps the newest time
+        clock.advance(Duration::milliseconds(-500));
+        let absorbed = kdb.sign("absorbed").unwrap();
+        assert!(absorbed.timestamp == first.timestamp);
+        assert!(kdb.clock_regression().is_none());
+
+        // A large one stops signing until the clock catches up
+        clock.advance(Duration::seconds(-10));
+        match kdb.sign("refused") {
+            Err(Error(ErrorKind::ClockRegression(ms), _)) => assert_eq!(ms, 10_500),
+            _ => panic!("signed with a clock that went backwards"),
+        }
+        assert!(kdb.clock_regression().is_some());
+
+        clock.set(*at("2018-03-01T10:15:01+00:00").inner());
+        let resumed = kdb.sign("resumed").unwrap();
+        assert!(resumed.timestamp > first.timestamp);
+        assert!(kdb.high_water() == Some(&resumed.timestamp));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn clock_regression_on_restart() {
+        let (kdb, clock) = test_db();
+        let stored = serde_json::to_string(&kdb).unwrap();
+
+        clock.set(*at("2018-03-01T09:00:00+00:00").inner());
+
+        // Starts up, but won't sign or log a key older than the newest one
+        let mut kdb: KeyDB = serde_json::from_str(&stored).unwrap();
+        kdb.set_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+        assert!(kdb.clock_regression().is_some());
+        match kdb.sign("refused") {
+            Err(Error(ErrorKind::ClockRegression(_), _)) => {}
+            _ => panic!("signed with a clock behind the newest key"),
+        }
+        kdb.prepare_next();
+        assert_eq!(kdb.key_count(), 1);
+
+        clock.set(*at("2018-03-01T10:20:00+00:00").inner());
+        assert!(kdb.clock_regression().is_none());
+        let signed = kdb.sign("resumed").unwrap();
+        assert!(signed.key_time == at("2018-03-01T10:20:00+00:00"));
+        assert_eq!(kdb.key_count(), 2);
+        assert!(kdb.verify_proofs().is_ok());
+    }
+
+    #[test]
+    fn caught_up_key_is_announced() {
+        let (kdb, clock) = test_db();
+        let stored = serde_json::to_string(&kdb).unwrap();
+
+        clock.set(*at("2018-03-01T09:00:00+00:00").inner());
+        let mut kdb: KeyDB = serde_json::from_str(&stored).unwrap();
+        kdb.set_clock(clock.clone());
+        kdb.defrost("test").unwrap();
+
+        clock.set(*at("2018-03-01T12:20:00+00:00").inner());
+        kdb.sign("resumed").unwrap();
+
+        // The back-filled keys and the one in use, each exactly once
+        let rotated = kdb.events_after(&at("2018-03-01T10:15:00+00:00"))
+            .into_iter()
+            .filter_map(|e| match e {
+                KeyEvent::Rotation { key } => Some(key.time().as_str().to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rotated,
+            vec![
+                "2018-03-01T11:00:00+00:00",
+                "2018-03-01T12:00:00+00:00",
+                "2018-03-01T12:20:00+00:00",
+            ]
+        );
+        assert!(kdb.current_public_key().unwrap().time() == &at("2018-03-01T12:20:00+00:00"));
+    }
+
+    #[test]
+    fn receipts_stamped_ahead_of_the_clock_verify() {
+        let (mut kdb, clock) = test_db();
+        let first = kdb.sign("first").unwrap();
+
+        // Within the tolerance, receipts keep the newest time
+        clock.advance(Duration::milliseconds(-500));
+        let absorbed = kdb.sign("absorbed").unwrap();
+        assert!(absorbed.timestamp == first.timestamp);
+        assert!(kdb.verify(&absorbed, VerifyMode::Strict).is_ok());
+    }
+
+    #[test]
+    fn keys_regenerate_from_master_seed() {
+        let sealed = MasterSeed::generate().unwrap().seal("test", 1000).unwrap();
+
+        let clock = Arc::new(ManualClock::at(START));
+        let mut kdb = KeyDB::with_clock(clock.clone());
+        kdb.set_master_seed(sealed.open("test").unwrap());
+        kdb.defrost("test").unwrap();
+        let stored = serde_json::to_string(&kdb).unwrap();
+
+        // Back-filled keys are derived too
+        clock.set(*at("2018-03-01T13:20:00+00:00").inner());
+        let mut kdb: KeyDB = serde_json::from_str(&stored).unwrap();
+        kdb.set_clock(clock.clone());
+        kdb.set_master_seed(sealed.open("test").unwrap());
+        kdb.defrost("test").unwrap();
+
+        kdb.prepare_next();
+        clock.set(*at("2018-03-01T14:00:00+00:00").inner());
+        kdb.get_current();
+
+        let seed = sealed.open("test").unwrap();
+        let checked = kdb.check_master_seed(&seed);
+        assert_eq!(checked.len(), 6);
+        assert!(checked.iter().all(|&(_, derived)| derived));
+
+        // Random keys aren't
+        let (random, _) = test_db();
+        assert!(random.check_master_seed(&seed).iter().all(|&(_, derived)| !derived));
+    }
+
+    #[test]
+    fn forced_rotation_replaces_derived_announcement() {
+        let seed = MasterSeed::generate().unwrap();
+        let sealed = seed.seal("test", 1000).unwrap();
+
+        let clock = Arc::new(ManualClock::at(START));
+        let mut kdb = KeyDB::with_clock(clock.clone());
+        kdb.set_master_seed(sealed.open("test").unwrap());
+        kdb.defrost("test").unwrap();
+
+        kdb.prepare_next();
+        let exposed = kdb.next_public_key().unwrap().clone();
+
+        clock.set(*at("2018-03-01T10:20:00+00:00").inner());
+        kdb.force_rotate(Some("suspected leak".into())).unwrap();
+        kdb.prepare_next();
+        let announced = kdb.next_public_key().unwrap().clone();
+        assert!(announced.time() == exposed.time());
+        assert!(announced.public_key() != exposed.public_key());
+
+        // The replacement is still derived, and used at the boundary
+        clock.set(*at("2018-03-01T11:00:30+00:00").inner());
+        let signed = kdb.sign("after").unwrap();
+        assert_eq!(signed.public_key, announced.public_key());
+        assert!(kdb.check_master_seed(&seed).iter().all(|&(_, derived)| derived));
+    }
+
+    #[test]
+    fn restarted_keys_rebuild_from_seed_alone() {
+        let sealed = MasterSeed::generate().unwrap().seal("test", 1000).unwrap();
+
+        let clock = Arc::new(ManualClock::at(START));
+        let mut kdb = KeyDB::with_clock(clock.clone());
+        kdb.set_master_seed(sealed.open("test").unwrap());
+        kdb.defrost("test").unwrap();
+
+        clock.set(*at("2018-03-01T10:20:00+00:00").inner());
+        kdb.force_rotate(Some("test".into())).unwrap();
+
+        // Restarted twice, once in the same period
+        for time in &["2018-03-01T10:50:00+00:00", "2018-03-01T12:30:00+00:00"] {
+            let stored = serde_json::to_string(&kdb).unwrap();
+            clock.set(*at(time).inner());
+            kdb = serde_json::from_str(&stored).unwrap();
+            kdb.set_clock(clock.clone());
+            kdb.set_master_seed(sealed.open("test").unwrap());
+            kdb.defrost("test").unwrap();
+        }
+
+        // Without the keystore, every period's first few generations
+        let seed = sealed.open("test").unwrap();
+        let rebuilt = seed
+            .derive_range(&at("2018-03-01T10:00:00+00:00"), &at("2018-03-01T12:59:59+00:00"), 3)
+            .into_iter()
+            .map(|key| key.pub_key_base64)
+            .collect::<Vec<_>>();
+
+        // First key, the forced rotation, the restart in its period,
+        // gap keys for 11:00 and 12:00, and the current key
+        assert_eq!(kdb.old_keys.len(), 6);
+        for key in &kdb.old_keys {
+            assert!(rebuilt.iter().any(|pk| pk == key.public_key()));
+        }
+    }
+}
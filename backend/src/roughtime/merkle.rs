@@ -0,0 +1,114 @@
+use ring::digest;
+
+/// Hashes in the tree are full SHA-512 outputs
+pub const HASH_LEN: usize = 64;
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub fn hash_leaf(nonce: &[u8]) -> Vec<u8> {
+    let mut ctx = digest::Context::new(&digest::SHA512);
+    ctx.update(&[LEAF_PREFIX]);
+    ctx.update(nonce);
+    ctx.finish().as_ref().to_vec()
+}
+
+pub fn hash_node(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut ctx = digest::Context::new(&digest::SHA512);
+    ctx.update(&[NODE_PREFIX]);
+    ctx.update(left);
+    ctx.update(right);
+    ctx.finish().as_ref().to_vec()
+}
+
+/// A Merkle tree over the nonces of one batch of requests. The leaf
+/// count is padded up to a power of two by repeating the last leaf
+pub struct Tree {
+    levels: Vec<Vec<Vec<u8>>>, // levels[0] are the leaves, the last is the root
+}
+
+impl Tree {
+    pub fn new(nonces: &[&[u8]]) -> Self {
+        assert!(!nonces.is_empty());
+
+        let mut leaves = nonces.iter().map(|n| hash_leaf(n)).collect::<Vec<_>>();
+        while !leaves.len().is_power_of_two() {
+            let last = leaves[leaves.len() - 1].clone();
+            leaves.push(last);
+        }
+
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| hash_node(&pair[0], &pair[1]))
+                .collect();
+            levels.push(next);
+        }
+
+        Tree { levels: levels }
+    }
+
+    pub fn root(&self) -> &[u8] {
+        &self.levels[self.levels.len() - 1][0]
+    }
+
+    /// Sibling hashes from leaf `index` up to (not including) the root,
+    /// concatenated as in the `PATH` tag
+    pub fn path(&self, mut index: usize) -> Vec<u8> {
+        let mut path = Vec::new();
+
+        for level in &self.levels[..self.levels.len() - 1] {
+            path.extend_from_slice(&level[index ^ 1]);
+            index >>= 1;
+        }
+
+        path
+    }
+}
+
+/// Recompute the root from a nonce, its index and its `PATH`
+pub fn root_from_path(nonce: &[u8], mut index: u32, path: &[u8]) -> Option<Vec<u8>> {
+    if path.len() % HASH_LEN != 0 {
+        return None;
+    }
+
+    let mut hash = hash_leaf(nonce);
+    for sibling in path.chunks(HASH_LEN) {
+        hash = if index & 1 == 0 {
+            hash_node(&hash, sibling)
+        } else {
+            hash_node(sibling, &hash)
+        };
+        index >>= 1;
+    }
+
+    if index != 0 {
+        return None;
+    }
+
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_lead_to_root() {
+        for count in 1..9 {
+            let nonces = (0..count).map(|n| vec![n as u8; 64]).collect::<Vec<_>>();
+            let refs = nonces.iter().map(|n| n.as_slice()).collect::<Vec<_>>();
+            let tree = Tree::new(&refs);
+
+            for (index, nonce) in nonces.iter().enumerate() {
+                let root = root_from_path(nonce, index as u32, &tree.path(index)).unwrap();
+                assert_eq!(root.as_slice(), tree.root());
+            }
+
+            // A nonce that isn't in the batch doesn't verify
+            let root = root_from_path(&[0xAA; 64], 0, &tree.path(0)).unwrap();
+            assert!(root.as_slice() != tree.root());
+        }
+    }
+}
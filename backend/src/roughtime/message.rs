@@ -0,0 +1,178 @@
+use errors::*;
+
+/// A Roughtime message: a map from tags to values. Values must be a
+/// multiple of four bytes long, and tags are kept in ascending order
+/// as the wire format requires
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Message {
+    fields: Vec<(u32, Vec<u8>)>,
+}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32> {
+    if data.len() < at + 4 {
+        bail!("Roughtime message truncated");
+    }
+
+    Ok(
+        (data[at] as u32) | ((data[at + 1] as u32) << 8) | ((data[at + 2] as u32) << 16) |
+            ((data[at + 3] as u32) << 24),
+    )
+}
+
+pub fn u32_le(value: u32) -> Vec<u8> {
+    (0..4).map(|n| (value >> (8 * n)) as u8).collect()
+}
+
+pub fn u64_le(value: u64) -> Vec<u8> {
+    (0..8).map(|n| (value >> (8 * n)) as u8).collect()
+}
+
+pub fn parse_u32(value: &[u8]) -> Result<u32> {
+    if value.len() != 4 {
+        bail!("Expected a 4 byte value");
+    }
+    read_u32(value, 0)
+}
+
+pub fn parse_u64(value: &[u8]) -> Result<u64> {
+    if value.len() != 8 {
+        bail!("Expected an 8 byte value");
+    }
+    Ok((read_u32(value, 0)? as u64) | ((read_u32(value, 4)? as u64) << 32))
+}
+
+impl Message {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a tag, replacing any existing value
+    pub fn insert(&mut self, tag: u32, value: Vec<u8>) {
+        match self.fields.binary_search_by_key(&tag, |&(t, _)| t) {
+            Ok(n) => self.fields[n].1 = value,
+            Err(n) => self.fields.insert(n, (tag, value)),
+        }
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&[u8]> {
+        self.fields
+            .binary_search_by_key(&tag, |&(t, _)| t)
+            .ok()
+            .map(|n| self.fields[n].1.as_slice())
+    }
+
+    /// Like `get`, but missing tags are an error
+    pub fn require(&self, tag: u32) -> Result<&[u8]> {
+        match self.get(tag) {
+            Some(v) => Ok(v),
+            None => bail!("Roughtime message is missing tag {:08x}", tag),
+        }
+    }
+
+    /// Length of the encoded message, in bytes
+    pub fn encoded_len(&self) -> usize {
+        let header = if self.fields.is_empty() {
+            4
+        } else {
+            4 + 8 * self.fields.len() - 4
+        };
+        header + self.fields.iter().map(|&(_, ref v)| v.len()).sum::<usize>()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        out.extend(u32_le(self.fields.len() as u32));
+
+        // Offsets of every value but the first, relative to the values
+        let mut offset = 0;
+        for &(_, ref value) in self.fields.iter().take(self.fields.len().saturating_sub(1)) {
+            offset += value.len() as u32;
+            out.extend(u32_le(offset));
+        }
+
+        for &(tag, _) in &self.fields {
+            out.extend(u32_le(tag));
+        }
+
+        for &(_, ref value) in &self.fields {
+            out.extend_from_slice(value);
+        }
+
+        out
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let count = read_u32(data, 0)? as usize;
+        if count == 0 {
+            return Ok(Self::new());
+        }
+
+        // The header is 8 bytes per tag, bound `count` before using it
+        if count > data.len() / 8 {
+            bail!("Roughtime message header truncated");
+        }
+        let header_len = 8 * count;
+
+        let mut offsets = vec![0usize];
+        for n in 0..(count - 1) {
+            offsets.push(read_u32(data, 4 + 4 * n)? as usize);
+        }
+
+        let tags_at = 4 + 4 * (count - 1);
+        let values = &data[header_len..];
+
+        let mut msg = Self::new();
+        let mut last_tag = None;
+
+        for n in 0..count {
+            let tag = read_u32(data, tags_at + 4 * n)?;
+            if last_tag.map_or(false, |t| tag <= t) {
+                bail!("Roughtime message tags are not in ascending order");
+            }
+            last_tag = Some(tag);
+
+            let start = offsets[n];
+            let end = if n + 1 < count { offsets[n + 1] } else { values.len() };
+
+            if start > end || end > values.len() || start % 4 != 0 || end % 4 != 0 {
+                bail!("Roughtime message has a bad offset");
+            }
+
+            msg.fields.push((tag, values[start..end].to_vec()));
+        }
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use roughtime::tags;
+
+    #[test]
+    fn round_trip() {
+        let mut msg = Message::new();
+        msg.insert(tags::RADI, u32_le(1_000_000));
+        msg.insert(tags::MIDP, u64_le(1_500_000_000_000_000));
+        msg.insert(tags::NONC, vec![7u8; 64]);
+
+        let raw = msg.encode();
+        assert_eq!(raw.len(), msg.encoded_len());
+
+        let parsed = Message::parse(&raw).unwrap();
+        assert_eq!(parsed, msg);
+        assert_eq!(parse_u32(parsed.require(tags::RADI).unwrap()).unwrap(), 1_000_000);
+        assert_eq!(
+            parse_u64(parsed.require(tags::MIDP).unwrap()).unwrap(),
+            1_500_000_000_000_000
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(Message::parse(&[]).is_err());
+        assert!(Message::parse(&[0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+        assert!(Message::parse(&[2, 0, 0, 0, 3, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! A Roughtime responder, using the hourly keys as the online key and a
+//! separate long-term key to sign their delegation certificates
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Deref;
+use std::path::Path;
+
+use ring::signature;
+use untrusted;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use file_utils::create_secret_file;
+use key_types::RANDOM;
+
+mod merkle;
+mod message;
+mod server;
+
+pub use self::message::Message;
+pub use self::server::{run, Server};
+
+/// Tags are four ASCII bytes, read as a little endian u32
+pub mod tags {
+    pub const SIG: u32 = 0x0047_4953;
+    pub const NONC: u32 = 0x434e_4f4e;
+    pub const DELE: u32 = 0x454c_4544;
+    pub const PATH: u32 = 0x4854_4150;
+    pub const RADI: u32 = 0x4944_4152;
+    pub const PUBK: u32 = 0x4b42_5550;
+    pub const MIDP: u32 = 0x5044_494d;
+    pub const SREP: u32 = 0x5045_5253;
+    pub const MINT: u32 = 0x544e_494d;
+    pub const ROOT: u32 = 0x544f_4f52;
+    pub const CERT: u32 = 0x5452_4543;
+    pub const MAXT: u32 = 0x5458_414d;
+    pub const INDX: u32 = 0x5844_4e49;
+    pub const PAD: u32 = 0xff44_4150;
+}
+
+/// Prefixed to `SREP` before it is signed by the online key
+pub const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\0";
+
+/// Prefixed to `DELE` before it is signed by the long-term key
+pub const DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\0";
+
+/// Requests are padded to at least this size, so the server can't be
+/// used to amplify traffic
+pub const MIN_REQUEST_LEN: usize = 1024;
+
+pub const NONCE_LEN: usize = 64;
+
+/// Microseconds since the Unix epoch, the unit of `MIDP`, `MINT` and `MAXT`
+pub fn to_micros(time: &ProveWhenTime) -> u64 {
+    let inner = time.inner();
+    (inner.timestamp() as u64) * 1_000_000 + inner.timestamp_subsec_micros() as u64
+}
+
+/// Load the long-term key, generating it on first use
+pub fn load_or_create_long_term_key(path: &Path) -> Result<signature::Ed25519KeyPair> {
+    if !path.exists() {
+        let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(RANDOM.deref())
+            .chain_err(|| "Failed to generate Roughtime long-term key")?;
+
+        create_secret_file(path)
+            .and_then(|mut f| f.write_all(&pkcs8[..]))
+            .chain_err(|| "Failed to write Roughtime long-term key")?;
+    }
+
+    let mut pkcs8 = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut pkcs8))
+        .chain_err(|| "Failed to read Roughtime long-term key")?;
+
+    signature::Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8))
+        .chain_err(|| "Roughtime long-term key is not a valid Ed25519 PKCS#8 document")
+}
+
+fn verify_sig(public_key: &[u8], context: &[u8], msg: &[u8], sig: &[u8]) -> Result<()> {
+    let mut signed = context.to_vec();
+    signed.extend_from_slice(msg);
+
+    signature::verify(
+        &signature::ED25519,
+        untrusted::Input::from(public_key),
+        untrusted::Input::from(&signed),
+        untrusted::Input::from(sig),
+    ).chain_err(|| ErrorKind::SignatureMismatch)
+}
+
+/// Check a response against the server's long-term public key and the
+/// nonce that was sent, returning the `(midpoint, radius)` in microseconds
+pub fn verify_response(long_term_key: &[u8], nonce: &[u8], response: &[u8]) -> Result<(u64, u32)> {
+    let resp = Message::parse(response)?;
+
+    // The delegation certificate binds the online key to a time window
+    let cert = Message::parse(resp.require(tags::CERT)?)?;
+    let dele_raw = cert.require(tags::DELE)?;
+    verify_sig(long_term_key, DELEGATION_CONTEXT, dele_raw, cert.require(tags::SIG)?)?;
+
+    let dele = Message::parse(dele_raw)?;
+    let online_key = dele.require(tags::PUBK)?;
+    let mint = message::parse_u64(dele.require(tags::MINT)?)?;
+    let maxt = message::parse_u64(dele.require(tags::MAXT)?)?;
+
+    // The signed response covers the Merkle root of the batch
+    let srep_raw = resp.require(tags::SREP)?;
+    verify_sig(online_key, RESPONSE_CONTEXT, srep_raw, resp.require(tags::SIG)?)?;
+
+    let srep = Message::parse(srep_raw)?;
+    let index = message::parse_u32(resp.require(tags::INDX)?)?;
+    let root = merkle::root_from_path(nonce, index, resp.require(tags::PATH)?);
+
+    if root.as_ref().map(|r| r.as_slice()) != Some(srep.require(tags::ROOT)?) {
+        bail!("Roughtime response does not cover our nonce");
+    }
+
+    let midpoint = message::parse_u64(srep.require(tags::MIDP)?)?;
+    let radius = message::parse_u32(srep.require(tags::RADI)?)?;
+
+    if midpoint < mint || midpoint > maxt {
+        bail!("Roughtime midpoint is outside of the delegation window");
+    }
+
+    Ok((midpoint, radius))
+}
+
+/// Build a padded request for `nonce`
+pub fn request(nonce: &[u8]) -> Vec<u8> {
+    let mut msg = Message::new();
+    msg.insert(tags::NONC, nonce.to_vec());
+    msg.insert(tags::PAD, Vec::new());
+
+    let padding = MIN_REQUEST_LEN.saturating_sub(msg.encoded_len());
+    msg.insert(tags::PAD, vec![0u8; padding]);
+
+    msg.encode()
+}
@@ -0,0 +1,262 @@
+use std::io::ErrorKind as IoErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration as StdDuration;
+
+use mvdb::Mvdb;
+use ring::signature::Ed25519KeyPair;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use key_types::SingleKeySet;
+use pub_key_storage::KeyDB;
+use scheduler::{SHUTDOWN, SHUTDOWN_POLL_MS};
+use settings::RoughtimeSettings;
+use status::{read_keystore, write_keystore, STATUS};
+
+use super::merkle::Tree;
+use super::message::{u32_le, u64_le, Message};
+use super::{tags, to_micros, DELEGATION_CONTEXT, MIN_REQUEST_LEN, NONCE_LEN, RESPONSE_CONTEXT};
+
+/// Largest datagram read from clients
+const MAX_DATAGRAM: usize = 1500;
+
+/// Signs batches of Roughtime requests
+pub struct Server {
+    long_term: Ed25519KeyPair,
+    radius_us: u32,
+
+    /// The encoded `CERT` for the online key it was made for
+    cert: Option<(String, Vec<u8>)>,
+}
+
+impl Server {
+    pub fn new(long_term: Ed25519KeyPair, radius_us: u32) -> Self {
+        Self {
+            long_term: long_term,
+            radius_us: radius_us,
+            cert: None,
+        }
+    }
+
+    /// Delegate to the online key for the period it is valid in
+    fn cert_for(&mut self, online: &SingleKeySet) -> Vec<u8> {
+        if let Some((ref pk, ref cert)) = self.cert {
+            if *pk == online.pub_key_base64 {
+                return cert.clone();
+            }
+        }
+
+        let mut dele = Message::new();
+        dele.insert(tags::PUBK, online.public_key_bytes().to_vec());
+        dele.insert(tags::MINT, u64_le(to_micros(&online.time_generated)));
+        dele.insert(
+            tags::MAXT,
+            u64_le(to_micros(&online.time_generated.next_period())),
+        );
+        let dele = dele.encode();
+
+        let mut to_sign = DELEGATION_CONTEXT.to_vec();
+        to_sign.extend_from_slice(&dele);
+
+        let mut cert = Message::new();
+        cert.insert(tags::SIG, self.long_term.sign(&to_sign).as_ref().to_vec());
+        cert.insert(tags::DELE, dele);
+        let cert = cert.encode();
+
+        self.cert = Some((online.pub_key_base64.clone(), cert.clone()));
+        cert
+    }
+
+    /// Answer a batch of requests with one signature. Invalid requests
+    /// get `None` and are dropped without a reply
+    pub fn respond(
+        &mut self,
+        requests: &[Vec<u8>],
+        online: &SingleKeySet,
+        now: &ProveWhenTime,
+    ) -> Vec<Option<Vec<u8>>> {
+        let nonces = requests
+            .iter()
+            .map(|req| nonce_of(req))
+            .collect::<Vec<Option<Vec<u8>>>>();
+
+        let valid = nonces
+            .iter()
+            .filter_map(|n| n.as_ref().map(|n| n.as_slice()))
+            .collect::<Vec<&[u8]>>();
+
+        if valid.is_empty() {
+            return vec![None; requests.len()];
+        }
+
+        let tree = Tree::new(&valid);
+
+        let mut srep = Message::new();
+        srep.insert(tags::RADI, u32_le(self.radius_us));
+        srep.insert(tags::MIDP, u64_le(to_micros(now)));
+        srep.insert(tags::ROOT, tree.root().to_vec());
+        let srep = srep.encode();
+
+        let mut to_sign = RESPONSE_CONTEXT.to_vec();
+        to_sign.extend_from_slice(&srep);
+        let sig = online.sign_bytes(&to_sign);
+
+        let cert = self.cert_for(online);
+
+        let mut index = 0;
+        nonces
+            .iter()
+            .map(|nonce| {
+                nonce.as_ref().map(|_| {
+                    let mut resp = Message::new();
+                    resp.insert(tags::SIG, sig.clone());
+                    resp.insert(tags::PATH, tree.path(index));
+                    resp.insert(tags::SREP, srep.clone());
+                    resp.insert(tags::CERT, cert.clone());
+                    resp.insert(tags::INDX, u32_le(index as u32));
+                    index += 1;
+                    resp.encode()
+                })
+            })
+            .collect()
+    }
+}
+
+/// The nonce of a well-formed, sufficiently padded request
+fn nonce_of(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < MIN_REQUEST_LEN {
+        return None;
+    }
+
+    match Message::parse(request) {
+        Ok(msg) => match msg.get(tags::NONC) {
+            Some(n) if n.len() == NONCE_LEN => Some(n.to_vec()),
+            _ => None,
+        },
+        Err(_) => None,
+    }
+}
+
+/// Wait for one request, then gather whatever else is already queued
+fn receive_batch(socket: &UdpSocket, max_batch: usize) -> Result<Vec<(Vec<u8>, SocketAddr)>> {
+    let mut buf = [0u8; MAX_DATAGRAM];
+    let mut batch = Vec::new();
+
+    // Blocks until the socket's read timeout, so shutdowns are noticed
+    socket.set_nonblocking(false).chain_err(|| "socket error")?;
+    match socket.recv_from(&mut buf) {
+        Ok((n, addr)) => batch.push((buf[..n].to_vec(), addr)),
+        Err(ref e) if e.kind() == IoErrorKind::WouldBlock || e.kind() == IoErrorKind::TimedOut => {
+            return Ok(batch)
+        }
+        Err(e) => return Err(Error::with_chain(e, "Roughtime receive failed")),
+    }
+
+    socket.set_nonblocking(true).chain_err(|| "socket error")?;
+    while batch.len() < max_batch {
+        match socket.recv_from(&mut buf) {
+            Ok((n, addr)) => batch.push((buf[..n].to_vec(), addr)),
+            Err(ref e) if e.kind() == IoErrorKind::WouldBlock => break,
+            Err(e) => return Err(Error::with_chain(e, "Roughtime receive failed")),
+        }
+    }
+
+    Ok(batch)
+}
+
+/// Serve Roughtime requests until a shutdown, signing with the current
+/// hourly key
+pub fn run(settings: RoughtimeSettings, keydb: Mvdb<KeyDB>) -> Result<()> {
+    let long_term = super::load_or_create_long_term_key(&settings.long_term_key)?;
+    println!(
+        "Roughtime long-term public key: {}",
+        ::base64::encode(long_term.public_key_bytes())
+    );
+
+    let socket = UdpSocket::bind(&settings.bind[..]).chain_err(|| "Failed to bind Roughtime socket")?;
+    socket
+        .set_read_timeout(Some(StdDuration::from_millis(SHUTDOWN_POLL_MS)))
+        .chain_err(|| "socket error")?;
+    let mut server = Server::new(long_term, settings.radius_us);
+
+    loop {
+        let batch = receive_batch(&socket, settings.max_batch)?;
+        if SHUTDOWN.requested() {
+            return Ok(());
+        }
+        if batch.is_empty() {
+            continue;
+        }
+
+        // Clients retry elsewhere if we stay silent
+        if STATUS.check_signing().is_err() {
+            continue;
+        }
+        let requests = batch.iter().map(|&(ref req, _)| req.clone()).collect::<Vec<_>>();
+
+        // The rotator normally keeps the key fresh, but don't sign with a
+        // key whose delegation has expired if it is late
+        if read_keystore(&keydb, |db| db.needs_rotation())? {
+            write_keystore(&keydb, |db| { db.get_current(); })?;
+        }
+
+        // Same as for receipts, don't hand out times from a clock that
+        // went backwards
+        let replies = read_keystore(&keydb, |db| {
+            if db.clock_regression().is_some() {
+                return Vec::new();
+            }
+            server.respond(&requests, db.current_key(), &db.now())
+        })?;
+
+        for (reply, &(_, addr)) in replies.iter().zip(batch.iter()) {
+            if let Some(ref reply) = *reply {
+                if let Err(e) = socket.send_to(reply, addr) {
+                    println!("Roughtime reply to {} failed: {}", addr, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Deref;
+
+    use untrusted;
+
+    use super::*;
+    use key_types::RANDOM;
+    use roughtime::{request, verify_response};
+
+    #[test]
+    fn batch_verifies() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(RANDOM.deref()).unwrap();
+        let long_term = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8)).unwrap();
+        let long_term_pk = long_term.public_key_bytes().to_vec();
+
+        let mut server = Server::new(long_term, 1_000_000);
+        let online = SingleKeySet::new();
+
+        let nonces = (0..5).map(|n| vec![n as u8; NONCE_LEN]).collect::<Vec<_>>();
+        let mut requests = nonces.iter().map(|n| request(n)).collect::<Vec<_>>();
+
+        // Too short to be answered
+        requests.push(vec![0u8; 64]);
+
+        let now = ProveWhenTime::now();
+        let replies = server.respond(&requests, &online, &now);
+        assert!(replies[5].is_none());
+
+        for (nonce, reply) in nonces.iter().zip(replies.iter()) {
+            let (midpoint, radius) =
+                verify_response(&long_term_pk, nonce, reply.as_ref().unwrap()).unwrap();
+            assert_eq!(midpoint, to_micros(&now));
+            assert_eq!(radius, 1_000_000);
+        }
+
+        // A reply doesn't verify for somebody else's nonce
+        let reply = replies[0].as_ref().unwrap();
+        assert!(verify_response(&long_term_pk, &nonces[1], reply).is_err());
+    }
+}
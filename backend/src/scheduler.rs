@@ -0,0 +1,230 @@
+//! Wakes the rotator at period boundaries and for periodic maintenance,
+//! and stops it on shutdown
+
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex};
+
+use chrono::{Duration, Utc};
+use mvdb::Mvdb;
+use ring::rand::SecureRandom;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use key_types::RANDOM;
+use pub_key_storage::KeyDB;
+use settings::RotationSettings;
+use status::{write_keystore, STATUS};
+
+/// How often loops blocked on I/O check for a shutdown, in milliseconds
+pub const SHUTDOWN_POLL_MS: u64 = 500;
+
+/// Set by the signal handler. Long running loops sleep on it, so they
+/// notice a shutdown right away
+pub struct Shutdown {
+    requested: Mutex<bool>,
+    wake: Condvar,
+}
+
+lazy_static! {
+    pub static ref SHUTDOWN: Shutdown = Shutdown::new();
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Shutdown {
+            requested: Mutex::new(false),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Ask everything sleeping on this to stop. Returns whether a
+    /// shutdown was already requested
+    pub fn request(&self) -> bool {
+        let mut requested = self.requested.lock().unwrap();
+        let was = *requested;
+        *requested = true;
+        self.wake.notify_all();
+        was
+    }
+
+    pub fn requested(&self) -> bool {
+        *self.requested.lock().unwrap()
+    }
+
+    /// Sleep for `duration`, like `sleep_until`
+    pub fn sleep(&self, duration: Duration) -> bool {
+        self.sleep_until(&ProveWhenTime::from_inner(Utc::now() + duration))
+    }
+
+    /// Sleep until `deadline`. Returns `false` early if a shutdown is
+    /// requested
+    pub fn sleep_until(&self, deadline: &ProveWhenTime) -> bool {
+        let mut requested = self.requested.lock().unwrap();
+
+        loop {
+            if *requested {
+                return false;
+            }
+
+            let left = deadline.inner().signed_duration_since(Utc::now());
+            if left <= Duration::zero() {
+                return true;
+            }
+
+            // Condvars may wake early, so check the time again
+            let left = left.to_std().unwrap_or_default();
+            requested = self.wake.wait_timeout(requested, left).unwrap().0;
+        }
+    }
+}
+
+/// When the rotator wakes next. Rotations happen exactly at the period
+/// boundary, while maintenance (heartbeat, preparing the next key, and
+/// catching a missed rotation) is jittered so it doesn't line up with
+/// other periodic work on the host
+pub struct Schedule {
+    maintenance: Duration,
+    next_maintenance: ProveWhenTime,
+}
+
+impl Schedule {
+    /// Maintenance is due right away
+    pub fn new(settings: &RotationSettings, now: &ProveWhenTime) -> Self {
+        Schedule {
+            maintenance: Duration::seconds(settings.maintenance_secs as i64),
+            next_maintenance: now.clone(),
+        }
+    }
+
+    /// The earlier of the next period boundary and the next maintenance
+    pub fn next_wake(&self, now: &ProveWhenTime) -> ProveWhenTime {
+        let boundary = now.next_period();
+
+        if self.next_maintenance < boundary {
+            self.next_maintenance.clone()
+        } else {
+            boundary
+        }
+    }
+
+    pub fn maintenance_due(&self, now: &ProveWhenTime) -> bool {
+        self.next_maintenance <= *now
+    }
+
+    /// Schedule the next maintenance one interval after `now`, moved by
+    /// `jitter` seconds
+    pub fn maintained(&mut self, now: &ProveWhenTime, jitter: i64) {
+        let next = *now.inner() + self.maintenance + Duration::seconds(jitter);
+        self.next_maintenance = ProveWhenTime::from_inner(next);
+    }
+}
+
+/// A random offset in `-max_secs..=max_secs`
+pub fn jitter(max_secs: u64) -> Result<i64> {
+    if max_secs == 0 {
+        return Ok(0);
+    }
+
+    let mut raw = [0u8; 4];
+    RANDOM
+        .deref()
+        .fill(&mut raw[..])
+        .chain_err(|| "Failed to generate jitter")?;
+
+    let value = raw.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+    Ok((value % (2 * max_secs + 1)) as i64 - max_secs as i64)
+}
+
+/// Keep the signing key rotated, and the next key prepared, until a
+/// shutdown is requested
+pub fn run(keydb: &Mvdb<KeyDB>, settings: &RotationSettings) -> Result<()> {
+    let mut schedule = Schedule::new(settings, &ProveWhenTime::now());
+
+    loop {
+        let wake = schedule.next_wake(&ProveWhenTime::now());
+        if !SHUTDOWN.sleep_until(&wake) {
+            return Ok(());
+        }
+
+        // The next key is prepared as soon as the current one is in use,
+        // so it is ready to be announced
+        write_keystore(keydb, |db| {
+            let _ = db.get_current();
+            db.prepare_next();
+        })?;
+
+        STATUS.rotator_heartbeat();
+
+        let now = ProveWhenTime::now();
+        if schedule.maintenance_due(&now) {
+            schedule.maintained(&now, jitter(settings.jitter_secs)?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use clock::at;
+
+    #[test]
+    fn wakes_at_boundaries_and_for_maintenance() {
+        let settings = RotationSettings::default();
+        let start = at("2018-03-01T10:58:00Z");
+        let mut schedule = Schedule::new(&settings, &start);
+
+        // Maintenance first, as it is due immediately
+        assert!(schedule.next_wake(&start) == start);
+        assert!(schedule.maintenance_due(&start));
+
+        schedule.maintained(&start, 30);
+        assert!(!schedule.maintenance_due(&start));
+
+        // The boundary comes before the next maintenance
+        assert!(schedule.next_wake(&start) == at("2018-03-01T11:00:00Z"));
+
+        let after = at("2018-03-01T11:00:00Z");
+        let expected = *start.inner() + Duration::seconds(settings.maintenance_secs as i64 + 30);
+        assert!(*schedule.next_wake(&after).inner() == expected);
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        assert_eq!(jitter(0).unwrap(), 0);
+        for _ in 0..100 {
+            let j = jitter(5).unwrap();
+            assert!(j >= -5 && j <= 5);
+        }
+    }
+
+    #[test]
+    fn jitter_stays_under_the_interval() {
+        let mut settings = RotationSettings::default();
+        assert!(settings.validate().is_ok());
+
+        // Maintenance could be due again as soon as it ran
+        settings.jitter_secs = settings.maintenance_secs;
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn shutdown_interrupts_sleep() {
+        let shutdown = Arc::new(Shutdown::new());
+        let far = ProveWhenTime::from_inner(Utc::now() + Duration::hours(1));
+
+        let sleeper = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || shutdown.sleep_until(&far))
+        };
+
+        assert!(!shutdown.request());
+        assert!(!sleeper.join().unwrap());
+        assert!(shutdown.request());
+
+        let past = ProveWhenTime::from_inner(Utc::now() - Duration::seconds(1));
+        assert!(Shutdown::new().sleep_until(&past));
+    }
+}
@@ -0,0 +1,457 @@
+use std::path::{Path, PathBuf};
+
+use chrono::Duration;
+use mvdb::helpers::just_load;
+
+use errors::*;
+
+/// Used in key statements if no `server_id` is configured
+pub const DEFAULT_SERVER_ID: &str = "provewhen.io";
+
+/// Accuracy stated in receipts while the clock hasn't been measured
+/// against time sources, in milliseconds
+pub const DEFAULT_ACCURACY_MS: u64 = 1000;
+
+/// How far the clock may fall behind the newest issued timestamp before
+/// signing stops, in milliseconds
+pub const DEFAULT_REGRESSION_TOLERANCE_MS: u64 = 1000;
+
+/// Server configuration, loaded from a JSON file. Every field has a
+/// default, so the file (and any section of it) may be left out
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// Where the key database is stored
+    pub keystore: PathBuf,
+
+    /// Identifies this server in the statement each key signs about
+    /// itself. Give every deployment its own
+    pub server_id: String,
+
+    pub upload: UploadSettings,
+    pub auth: AuthSettings,
+    pub cors: CorsSettings,
+    pub frontend: FrontendSettings,
+    pub rotation: RotationSettings,
+    pub events: EventSettings,
+    pub roughtime: RoughtimeSettings,
+    pub time: TimeSettings,
+    pub time_sources: TimeSourceSettings,
+    pub hooks: HookSettings,
+    pub key_seed: KeySeedSettings,
+    pub admin: AdminSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            keystore: PathBuf::from("keystore.json"),
+            server_id: DEFAULT_SERVER_ID.into(),
+            upload: UploadSettings::default(),
+            auth: AuthSettings::default(),
+            cors: CorsSettings::default(),
+            frontend: FrontendSettings::default(),
+            rotation: RotationSettings::default(),
+            events: EventSettings::default(),
+            roughtime: RoughtimeSettings::default(),
+            time: TimeSettings::default(),
+            time_sources: TimeSourceSettings::default(),
+            hooks: HookSettings::default(),
+            key_seed: KeySeedSettings::default(),
+            admin: AdminSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Load settings from `path`, or use the defaults if it doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let settings: Self = just_load(path)?;
+        settings.rotation.validate()?;
+        Ok(settings)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct UploadSettings {
+    /// Largest file accepted by the streaming upload endpoint, in bytes
+    pub max_bytes: u64,
+}
+
+impl Default for UploadSettings {
+    fn default() -> Self {
+        Self { max_bytes: 1024 * 1024 * 1024 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// Reject signing requests that don't present an API key
+    pub required: bool,
+
+    /// API key file, managed with `provewhen apikey ...`
+    pub keys_file: PathBuf,
+
+    // Defaults for clients with an API key. Keys may override these
+    pub per_key_per_minute: u32,
+    pub per_key_daily: u64,
+
+    // Limits for anonymous clients, accounted by IP address
+    pub per_ip_per_minute: u32,
+    pub per_ip_daily: u64,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            required: false,
+            keys_file: PathBuf::from("apikeys.json"),
+            per_key_per_minute: 600,
+            per_key_daily: 100_000,
+            per_ip_per_minute: 30,
+            per_ip_daily: 1_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsSettings {
+    pub enabled: bool,
+
+    /// Origins allowed to call the API, or `"*"` for any origin
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+
+    /// Response headers readable by browser clients
+    pub expose_headers: Vec<String>,
+
+    /// How long browsers may cache a preflight response
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+
+        Self {
+            enabled: true,
+            allowed_origins: strings(&["*"]),
+            allowed_methods: strings(&["GET", "POST", "OPTIONS"]),
+            allowed_headers: strings(&["Accept", "Content-Type", "Authorization", "X-Api-Key"]),
+            expose_headers: strings(&[
+                "X-RateLimit-Limit",
+                "X-RateLimit-Remaining",
+                "X-Quota-Limit",
+                "X-Quota-Remaining",
+                "X-Quota-Reset",
+            ]),
+            max_age_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FrontendSettings {
+    /// Serve the static site alongside the API
+    pub enabled: bool,
+
+    /// Directory containing `index.html` and `static/`
+    pub root: PathBuf,
+
+    /// `Cache-Control` max-age for assets. HTML pages are never cached
+    pub cache_max_age_secs: u64,
+}
+
+impl Default for FrontendSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            root: PathBuf::from("../frontend"),
+            cache_max_age_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RotationSettings {
+    /// How long before a rotation the next key is generated and announced
+    /// through `/key/current`. Zero disables announcements
+    pub announce_secs: i64,
+
+    /// How often the rotator checks in and checks the keys between
+    /// rotations, which happen at the period boundary regardless
+    pub maintenance_secs: u64,
+
+    /// Maintenance runs up to this many seconds early or late. Must be
+    /// less than `maintenance_secs`
+    pub jitter_secs: u64,
+}
+
+impl Default for RotationSettings {
+    fn default() -> Self {
+        Self {
+            announce_secs: 10 * 60,
+            maintenance_secs: 180,
+            jitter_secs: 30,
+        }
+    }
+}
+
+impl RotationSettings {
+    /// Jitter as large as the interval could schedule maintenance for
+    /// right now, over and over
+    pub fn validate(&self) -> Result<()> {
+        if self.jitter_secs >= self.maintenance_secs {
+            bail!(
+                "rotation.jitter_secs ({}) must be less than rotation.maintenance_secs ({})",
+                self.jitter_secs,
+                self.maintenance_secs
+            );
+        }
+        Ok(())
+    }
+
+    /// How long the rotator may go without checking in before it is
+    /// considered dead: three of its longest maintenance intervals
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::seconds(3 * (self.maintenance_secs + self.jitter_secs) as i64)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct EventSettings {
+    /// Most concurrent `/key/events` streams. Each one holds a worker
+    /// thread, so keep this well below Rocket's `workers`
+    pub max_streams: usize,
+}
+
+impl Default for EventSettings {
+    fn default() -> Self {
+        Self { max_streams: 8 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RoughtimeSettings {
+    /// Answer Roughtime requests over UDP
+    pub enabled: bool,
+    pub bind: String,
+
+    /// PKCS#8 file holding the long-term key, created if missing. Clients
+    /// pin its public key, so keep it safe
+    pub long_term_key: PathBuf,
+
+    /// Uncertainty reported in responses, in microseconds
+    pub radius_us: u32,
+
+    /// Most requests answered with a single signature
+    pub max_batch: usize,
+}
+
+impl Default for RoughtimeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: "0.0.0.0:2002".into(),
+            long_term_key: PathBuf::from("roughtime.pk8"),
+            radius_us: 1_000_000,
+            max_batch: 64,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TimeSettings {
+    /// Accuracy bound stated in receipts and by `/time` while the time
+    /// sources haven't measured the clock, in milliseconds. This should
+    /// cover the error of the host's clock synchronisation
+    pub accuracy_ms: u64,
+
+    /// Smaller backwards steps of the clock are absorbed by stamping
+    /// receipts with the newest issued time. Beyond this, signing stops
+    /// until the clock catches up
+    pub regression_tolerance_ms: u64,
+}
+
+impl Default for TimeSettings {
+    fn default() -> Self {
+        Self {
+            accuracy_ms: DEFAULT_ACCURACY_MS,
+            regression_tolerance_ms: DEFAULT_REGRESSION_TOLERANCE_MS,
+        }
+    }
+}
+
+/// A server the clock is checked against
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TimeServer {
+    /// `host:port` of an NTP server, queried over SNTP
+    Ntp { address: String },
+
+    /// `host:port` of a Roughtime server, and its base64 long-term key
+    Roughtime { address: String, public_key: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TimeSourceSettings {
+    /// Servers to cross-check the clock against. None disables the check
+    pub servers: Vec<TimeServer>,
+
+    pub interval_secs: u64,
+
+    /// How long to wait for each server's reply, in milliseconds
+    pub timeout_ms: u64,
+
+    /// Signing pauses while the clock is further than this from the
+    /// servers' consensus, in milliseconds
+    pub max_offset_ms: u64,
+}
+
+impl Default for TimeSourceSettings {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            interval_secs: 5 * 60,
+            timeout_ms: 2000,
+            max_offset_ms: 1000,
+        }
+    }
+}
+
+/// What a hook does with an event
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HookAction {
+    /// Write each event to a JSON file in `path`
+    Directory { path: PathBuf },
+
+    /// Run `program` with the event as JSON on stdin, and its name in
+    /// `PROVEWHEN_EVENT`. A non-zero exit status is a failed delivery
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+
+    /// POST the event as JSON. Only `http://` URLs are supported, use a
+    /// command hook with `curl` for anything else
+    Webhook { url: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Hook {
+    /// Identifies the hook in the delivery state and metrics
+    pub name: String,
+
+    /// Event names (`rotation`, `gap`, `revocation`) to deliver. Empty
+    /// means all of them
+    #[serde(default)]
+    pub events: Vec<String>,
+
+    pub action: HookAction,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HookSettings {
+    /// None disables hooks
+    pub targets: Vec<Hook>,
+
+    /// Pending, and recently finished, deliveries
+    pub state_file: PathBuf,
+
+    /// Deliveries are given up on, and reported as failed, after this
+    /// many attempts
+    pub max_attempts: u32,
+
+    /// Delay before the first retry, doubling after each further failure
+    pub retry_secs: u64,
+
+    /// Longest a command or webhook may take
+    pub timeout_secs: u64,
+}
+
+impl Default for HookSettings {
+    fn default() -> Self {
+        Self {
+            targets: Vec::new(),
+            state_file: PathBuf::from("hooks_state.json"),
+            max_attempts: 8,
+            retry_secs: 30,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct KeySeedSettings {
+    /// Derive every key from the master seed, its period and a counter,
+    /// instead of generating it at random. Lost keys can then be
+    /// regenerated from a backup of the seed alone
+    pub enabled: bool,
+
+    /// The master seed, sealed with a passphrase. Created by
+    /// `provewhen seed generate`
+    pub seed_file: PathBuf,
+
+    /// Environment variable holding the passphrase
+    pub passphrase_env: String,
+
+    /// PBKDF2 iterations used when sealing a new seed. Sealed seeds
+    /// record their own count
+    pub iterations: u32,
+}
+
+impl Default for KeySeedSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seed_file: PathBuf::from("master_seed.json"),
+            passphrase_env: "PROVEWHEN_SEED_PASSPHRASE".into(),
+            iterations: 100_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AdminSettings {
+    /// Serve the admin API on `127.0.0.1:<port>`. It is never exposed on
+    /// other interfaces
+    pub enabled: bool,
+    pub port: u16,
+
+    /// Bearer token for the admin API, generated on first start. The
+    /// `admin` subcommand reads it from here
+    pub token_file: PathBuf,
+
+    /// Every admin request is appended here
+    pub audit_log: PathBuf,
+}
+
+impl Default for AdminSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 8001,
+            token_file: PathBuf::from("admin.token"),
+            audit_log: PathBuf::from("admin_audit.log"),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration as StdDuration, Instant};
+
+use chrono::Duration;
+use mvdb::Mvdb;
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use metrics::METRICS;
+use pub_key_storage::KeyDB;
+use timesource::TIME_SOURCES;
+
+/// Process-wide state that isn't part of the key database, reported
+/// by the health endpoints
+pub struct ServiceStatus {
+    rotator_heartbeat: Mutex<Option<ProveWhenTime>>,
+    last_keystore_write: Mutex<Option<ProveWhenTime>>,
+
+    /// Set by operators through the admin API. Not persisted, a restart
+    /// resumes signing
+    signing_paused: AtomicBool,
+
+    /// HTTP requests being handled, counted by the `InFlight` fairing
+    requests_in_flight: AtomicUsize,
+}
+
+lazy_static! {
+    pub static ref STATUS: ServiceStatus = ServiceStatus {
+        rotator_heartbeat: Mutex::new(None),
+        last_keystore_write: Mutex::new(None),
+        signing_paused: AtomicBool::new(false),
+        requests_in_flight: AtomicUsize::new(0),
+    };
+}
+
+impl ServiceStatus {
+    pub fn rotator_heartbeat(&self) {
+        *self.rotator_heartbeat.lock().unwrap() = Some(ProveWhenTime::now());
+    }
+
+    pub fn last_rotator_heartbeat(&self) -> Option<ProveWhenTime> {
+        self.rotator_heartbeat.lock().unwrap().clone()
+    }
+
+    /// Whether the rotator has checked in within `timeout`
+    pub fn rotator_alive(&self, timeout: Duration) -> bool {
+        match self.last_rotator_heartbeat() {
+            Some(t) => age(&t) < timeout,
+            None => false,
+        }
+    }
+
+    pub fn keystore_written(&self) {
+        *self.last_keystore_write.lock().unwrap() = Some(ProveWhenTime::now());
+    }
+
+    pub fn last_keystore_write(&self) -> Option<ProveWhenTime> {
+        self.last_keystore_write.lock().unwrap().clone()
+    }
+
+    /// Pause or resume signing. Returns the previous state
+    pub fn set_signing_paused(&self, paused: bool) -> bool {
+        self.signing_paused.swap(paused, Ordering::SeqCst)
+    }
+
+    pub fn signing_paused(&self) -> bool {
+        self.signing_paused.load(Ordering::SeqCst)
+    }
+
+    pub fn request_started(&self) {
+        self.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn request_finished(&self) {
+        self.requests_in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Wait for the requests being handled to finish, for at most
+    /// `timeout`. Returns how many are still running
+    pub fn drain_requests(&self, timeout: StdDuration) -> usize {
+        let started = Instant::now();
+
+        loop {
+            let running = self.requests_in_flight.load(Ordering::SeqCst);
+            if running == 0 || started.elapsed() >= timeout {
+                return running;
+            }
+            thread::sleep(StdDuration::from_millis(50));
+        }
+    }
+
+    /// Called before anything is signed for a client
+    pub fn check_signing(&self) -> Result<()> {
+        if self.signing_paused() {
+            bail!(ErrorKind::SigningPaused);
+        }
+        TIME_SOURCES.check_signing()
+    }
+}
+
+/// Time elapsed since `time`
+pub fn age(time: &ProveWhenTime) -> Duration {
+    ProveWhenTime::now().inner().signed_duration_since(*time.inner())
+}
+
+/// Mutably access the key database, which writes it back to disk, and
+/// record the write if it succeeded
+pub fn write_keystore<F, T>(keydb: &Mvdb<KeyDB>, func: F) -> Result<T>
+where
+    F: FnOnce(&mut KeyDB) -> T,
+{
+    let waiting = Instant::now();
+    let mut writing = None;
+
+    let rslt = keydb.access_mut(|db| {
+        METRICS.keystore_lock_wait_seconds.observe(waiting.elapsed());
+        let rslt = func(db);
+        writing = Some(Instant::now());
+        rslt
+    })?;
+
+    if let Some(start) = writing {
+        METRICS.keystore_write_seconds.observe(start.elapsed());
+    }
+
+    STATUS.keystore_written();
+    Ok(rslt)
+}
+
+/// Immutably access the key database
+pub fn read_keystore<F, T>(keydb: &Mvdb<KeyDB>, func: F) -> Result<T>
+where
+    F: FnOnce(&KeyDB) -> T,
+{
+    let waiting = Instant::now();
+
+    Ok(keydb.access(|db| {
+        METRICS.keystore_lock_wait_seconds.observe(waiting.elapsed());
+        func(db)
+    })?)
+}
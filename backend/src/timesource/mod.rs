@@ -0,0 +1,341 @@
+//! Cross-checks the host's clock against NTP and Roughtime servers, and
+//! stops signing while it disagrees with them
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use base64;
+use chrono::{Duration, Utc};
+
+use datetime_utils::ProveWhenTime;
+use errors::*;
+use metrics::METRICS;
+use scheduler::SHUTDOWN;
+use settings::{TimeServer, TimeSourceSettings};
+
+mod ntp;
+mod roughtime_client;
+
+/// Measurements older than this many check intervals don't vouch for
+/// the clock anymore
+const STALE_AFTER_INTERVALS: i32 = 3;
+
+/// One server's reading of our clock
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// How far the server's clock is ahead of ours
+    pub offset: Duration,
+
+    /// Bound on the error of `offset`, from the round trip and from the
+    /// server's own error
+    pub uncertainty: Duration,
+}
+
+/// The servers' consensus on our clock
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub offset: Duration,
+    pub uncertainty: Duration,
+    pub servers: usize,
+    pub responses: usize,
+    pub measured_at: ProveWhenTime,
+}
+
+impl Measurement {
+    /// Whether most configured servers answered. A minority could be
+    /// wrong or spoofed, NTP isn't authenticated
+    pub fn has_quorum(&self) -> bool {
+        self.responses * 2 > self.servers
+    }
+
+    /// Bound on how far the clock is from the servers' time
+    pub fn accuracy(&self) -> Duration {
+        abs(self.offset) + self.uncertainty
+    }
+}
+
+fn abs(d: Duration) -> Duration {
+    if d < Duration::zero() {
+        -d
+    } else {
+        d
+    }
+}
+
+/// Unix time in microseconds
+fn now_micros() -> i64 {
+    let now = Utc::now();
+    now.timestamp() * 1_000_000 + now.timestamp_subsec_micros() as i64
+}
+
+/// A socket to query `address` from, and the server's resolved address
+fn connect(address: &str, timeout: StdDuration) -> Result<(UdpSocket, SocketAddr)> {
+    let server = address
+        .to_socket_addrs()
+        .chain_err(|| format!("Can't resolve {}", address))?
+        .next()
+        .ok_or_else(|| Error::from(format!("{} has no address", address)))?;
+
+    let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local).chain_err(|| "Failed to bind time source socket")?;
+    socket
+        .set_read_timeout(Some(timeout))
+        .chain_err(|| "Failed to set time source timeout")?;
+
+    Ok((socket, server))
+}
+
+/// Median offset of the samples. The uncertainty covers the majority of
+/// samples closest to the median, so a minority of servers with a wrong
+/// clock can't widen it
+pub fn combine(samples: &[Sample]) -> Option<(Duration, Duration)> {
+    if samples.is_empty() {
+        return None;
+    }
+
+    let mut offsets = samples.iter().map(|s| s.offset).collect::<Vec<_>>();
+    offsets.sort();
+    let n = offsets.len();
+    let median = if n % 2 == 1 {
+        offsets[n / 2]
+    } else {
+        (offsets[n / 2 - 1] + offsets[n / 2]) / 2
+    };
+
+    let mut bounds = samples
+        .iter()
+        .map(|s| (abs(s.offset - median), s.uncertainty + abs(s.offset - median)))
+        .collect::<Vec<_>>();
+    bounds.sort();
+
+    let uncertainty = bounds[..n / 2 + 1].iter().map(|b| b.1).max().unwrap();
+
+    Some((median, uncertainty))
+}
+
+struct State {
+    latest: Option<Measurement>,
+    max_offset: Duration,
+    stale_after: Duration,
+}
+
+/// The latest measurement, and whether the clock may be signed with
+pub struct TimeSources {
+    state: Mutex<State>,
+}
+
+lazy_static! {
+    pub static ref TIME_SOURCES: TimeSources = TimeSources::new(&TimeSourceSettings::default());
+}
+
+impl TimeSources {
+    pub fn new(settings: &TimeSourceSettings) -> Self {
+        let sources = TimeSources {
+            state: Mutex::new(State {
+                latest: None,
+                max_offset: Duration::zero(),
+                stale_after: Duration::zero(),
+            }),
+        };
+        sources.configure(settings);
+        sources
+    }
+
+    pub fn configure(&self, settings: &TimeSourceSettings) {
+        let mut state = self.state.lock().unwrap();
+        state.max_offset = Duration::milliseconds(settings.max_offset_ms as i64);
+        state.stale_after = Duration::seconds(settings.interval_secs as i64) * STALE_AFTER_INTERVALS;
+    }
+
+    /// Keep `measurement`, unless too few servers answered for it to
+    /// pause signing or to replace the previous one
+    pub fn record(&self, measurement: Measurement) {
+        if !measurement.has_quorum() {
+            println!(
+                "WARNING: only {} of {} time sources answered, the measurement is ignored",
+                measurement.responses,
+                measurement.servers
+            );
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        let was_off = state.latest.as_ref().map_or(false, |m| abs(m.offset) > state.max_offset);
+        let is_off = abs(measurement.offset) > state.max_offset;
+
+        if is_off && !was_off {
+            println!(
+                "ALERT: the clock is {}ms off from the time sources, signing is paused",
+                measurement.offset.num_milliseconds()
+            );
+        } else if was_off && !is_off {
+            println!("The clock agrees with the time sources again, signing resumes");
+        }
+
+        state.latest = Some(measurement);
+    }
+
+    /// The latest measurement, however old
+    pub fn latest(&self) -> Option<Measurement> {
+        self.state.lock().unwrap().latest.clone()
+    }
+
+    /// Bound on the clock's error, if it was measured recently
+    pub fn accuracy(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+
+        state.latest.as_ref().and_then(|m| {
+            let age = Utc::now().signed_duration_since(*m.measured_at.inner());
+            if age < state.stale_after {
+                Some(m.accuracy())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The clock's offset, if it is too large to sign with. A bad
+    /// measurement keeps signing paused even once it is stale, until
+    /// the servers answer again
+    pub fn excess_offset(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .latest
+            .as_ref()
+            .map(|m| m.offset)
+            .and_then(|offset| if abs(offset) > state.max_offset { Some(offset) } else { None })
+    }
+
+    pub fn check_signing(&self) -> Result<()> {
+        if let Some(offset) = self.excess_offset() {
+            bail!(ErrorKind::ClockOffset(offset.num_milliseconds()));
+        }
+        Ok(())
+    }
+}
+
+fn address(server: &TimeServer) -> &str {
+    match *server {
+        TimeServer::Ntp { ref address } | TimeServer::Roughtime { ref address, .. } => address,
+    }
+}
+
+fn query(server: &TimeServer, timeout: StdDuration) -> Result<Sample> {
+    match *server {
+        TimeServer::Ntp { ref address } => ntp::query(address, timeout),
+        TimeServer::Roughtime {
+            ref address,
+            ref public_key,
+        } => {
+            let key = base64::decode(public_key)
+                .chain_err(|| format!("Roughtime public key for {} is not valid base64", address))?;
+            roughtime_client::query(address, &key, timeout)
+        }
+    }
+}
+
+/// Query every server once, and combine the answers
+pub fn measure(settings: &TimeSourceSettings) -> Option<Measurement> {
+    let timeout = StdDuration::from_millis(settings.timeout_ms);
+
+    let samples = settings
+        .servers
+        .iter()
+        .filter_map(|server| match query(server, timeout) {
+            Ok(sample) => Some(sample),
+            Err(e) => {
+                println!("Time source {} failed: {}", address(server), e);
+                METRICS.time_source_failures.inc(address(server));
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    combine(&samples).map(|(offset, uncertainty)| Measurement {
+        offset: offset,
+        uncertainty: uncertainty,
+        servers: settings.servers.len(),
+        responses: samples.len(),
+        measured_at: ProveWhenTime::now(),
+    })
+}
+
+/// Check the clock against the time sources forever
+pub fn run(settings: TimeSourceSettings) {
+    TIME_SOURCES.configure(&settings);
+
+    loop {
+        match measure(&settings) {
+            Some(measurement) => TIME_SOURCES.record(measurement),
+            None => println!("WARNING: no time source answered, the clock is unchecked"),
+        }
+
+        if !SHUTDOWN.sleep(Duration::seconds(settings.interval_secs as i64)) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(offset_ms: i64, uncertainty_ms: i64) -> Sample {
+        Sample {
+            offset: Duration::milliseconds(offset_ms),
+            uncertainty: Duration::milliseconds(uncertainty_ms),
+        }
+    }
+
+    #[test]
+    fn consensus_ignores_a_falseticker() {
+        let (offset, uncertainty) =
+            combine(&[sample(10, 5), sample(-5000, 5), sample(20, 5)]).unwrap();
+        assert_eq!(offset, Duration::milliseconds(10));
+        assert_eq!(uncertainty, Duration::milliseconds(15));
+
+        let (offset, uncertainty) = combine(&[sample(10, 5), sample(30, 5)]).unwrap();
+        assert_eq!(offset, Duration::milliseconds(20));
+        assert_eq!(uncertainty, Duration::milliseconds(15));
+
+        assert!(combine(&[]).is_none());
+    }
+
+    #[test]
+    fn large_offset_pauses_signing() {
+        let mut settings = TimeSourceSettings::default();
+        settings.max_offset_ms = 500;
+        let sources = TimeSources::new(&settings);
+
+        let answered = |offset_ms, responses| Measurement {
+            offset: Duration::milliseconds(offset_ms),
+            uncertainty: Duration::milliseconds(20),
+            servers: 3,
+            responses: responses,
+            measured_at: ProveWhenTime::now(),
+        };
+        let measured = |offset_ms| answered(offset_ms, 3);
+
+        assert!(sources.check_signing().is_ok());
+        assert!(sources.accuracy().is_none());
+
+        sources.record(measured(-100));
+        assert!(sources.check_signing().is_ok());
+        assert_eq!(sources.accuracy(), Some(Duration::milliseconds(120)));
+
+        // One server of three can't pause signing, or replace a good
+        // measurement
+        sources.record(answered(-5000, 1));
+        assert!(sources.check_signing().is_ok());
+        assert_eq!(sources.accuracy(), Some(Duration::milliseconds(120)));
+
+        sources.record(measured(-800));
+        match *sources.check_signing().unwrap_err().kind() {
+            ErrorKind::ClockOffset(ms) => assert_eq!(ms, -800),
+            _ => panic!("expected a clock offset error"),
+        }
+    }
+}
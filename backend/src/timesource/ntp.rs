@@ -0,0 +1,191 @@
+//! A minimal SNTP client (RFC 4330)
+
+use std::time::Duration as StdDuration;
+
+use chrono::Duration;
+
+use errors::*;
+
+use super::{connect, now_micros, Sample};
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch
+const NTP_UNIX_OFFSET: i64 = 2_208_988_800;
+
+const PACKET_LEN: usize = 48;
+
+/// Leap indicator 0, version 4, mode 3 (client)
+const CLIENT_HEADER: u8 = 0b00_100_011;
+
+const MODE_SERVER: u8 = 4;
+
+/// Leap indicator of a server whose clock isn't synchronised
+const LEAP_ALARM: u8 = 3;
+
+fn read_u32(b: &[u8]) -> u32 {
+    (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+}
+
+fn write_u32(b: &mut [u8], value: u32) {
+    b[0] = (value >> 24) as u8;
+    b[1] = (value >> 16) as u8;
+    b[2] = (value >> 8) as u8;
+    b[3] = value as u8;
+}
+
+/// Encode Unix microseconds as an NTP timestamp. The seconds wrap at the
+/// end of era 0 in 2036, as they do on the wire
+fn to_ntp(micros: i64) -> [u8; 8] {
+    let secs = micros / 1_000_000 + NTP_UNIX_OFFSET;
+    let frac = ((micros % 1_000_000) << 32) / 1_000_000;
+
+    let mut ts = [0u8; 8];
+    write_u32(&mut ts[..4], secs as u32);
+    write_u32(&mut ts[4..], frac as u32);
+    ts
+}
+
+/// Decode an NTP timestamp to Unix microseconds. Timestamps with the top
+/// bit clear are taken to be in era 1, from 2036 on (RFC 4330 section 3)
+fn from_ntp(ts: &[u8]) -> i64 {
+    let mut secs = read_u32(&ts[..4]) as i64;
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    let frac = read_u32(&ts[4..]) as i64;
+
+    (secs - NTP_UNIX_OFFSET) * 1_000_000 + ((frac * 1_000_000) >> 32)
+}
+
+/// Decode a 16.16 fixed point number of seconds to microseconds
+fn short_micros(b: &[u8]) -> i64 {
+    (read_u32(b) as i64 * 1_000_000) >> 16
+}
+
+/// Check `reply` answers `request`, and work out our clock's offset from
+/// the four timestamps of the exchange
+fn parse_reply(request: &[u8], reply: &[u8], t1: i64, t4: i64) -> Result<Sample> {
+    if reply.len() < PACKET_LEN {
+        bail!("NTP reply is too short");
+    }
+    if reply[0] & 0x07 != MODE_SERVER {
+        bail!("NTP reply is not from a server");
+    }
+    if reply[0] >> 6 == LEAP_ALARM {
+        bail!("NTP server is not synchronised");
+    }
+    if reply[1] == 0 || reply[1] > 15 {
+        bail!("NTP server sent a kiss-o'-death or is unsynchronised (stratum {})", reply[1]);
+    }
+    if reply[24..32] != request[40..48] {
+        bail!("NTP reply does not answer our request");
+    }
+
+    let root_delay = short_micros(&reply[4..8]);
+    let root_dispersion = short_micros(&reply[8..12]);
+    let t2 = from_ntp(&reply[32..40]);
+    let t3 = from_ntp(&reply[40..48]);
+
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    let delay = ((t4 - t1) - (t3 - t2)).max(0);
+
+    Ok(Sample {
+        offset: Duration::microseconds(offset),
+        uncertainty: Duration::microseconds(delay / 2 + root_delay / 2 + root_dispersion),
+    })
+}
+
+/// Ask the NTP server at `address` for its time
+pub fn query(address: &str, timeout: StdDuration) -> Result<Sample> {
+    let (socket, server) = connect(address, timeout)?;
+
+    let mut request = [0u8; PACKET_LEN];
+    request[0] = CLIENT_HEADER;
+
+    let t1 = now_micros();
+    request[40..48].copy_from_slice(&to_ntp(t1));
+    socket
+        .send_to(&request, server)
+        .chain_err(|| format!("Failed to query {}", address))?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        let (n, from) = socket
+            .recv_from(&mut buf)
+            .chain_err(|| format!("No reply from {}", address))?;
+        let t4 = now_micros();
+
+        if from == server {
+            return parse_reply(&request, &buf[..n], t1, t4);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use super::*;
+
+    /// A stand-in server that answers a single request
+    fn stand_in<F>(answer: F) -> String
+    where
+        F: FnOnce(&[u8]) -> Vec<u8> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut buf = [0u8; PACKET_LEN];
+            let (_, from) = socket.recv_from(&mut buf).unwrap();
+            socket.send_to(&answer(&buf), from).unwrap();
+        });
+
+        address
+    }
+
+    /// The reply of a server whose clock is `skew_us` ahead of ours
+    fn reply(request: &[u8], skew_us: i64, stratum: u8) -> Vec<u8> {
+        let mut reply = vec![0u8; PACKET_LEN];
+        reply[0] = 0b00_100_100;
+        reply[1] = stratum;
+        write_u32(&mut reply[8..12], (1 << 16) / 100); // 10ms root dispersion
+        reply[24..32].copy_from_slice(&request[40..48]);
+
+        let now = to_ntp(now_micros() + skew_us);
+        reply[32..40].copy_from_slice(&now);
+        reply[40..48].copy_from_slice(&now);
+        reply
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        // 2018, in era 0, and 2040, in era 1
+        for &micros in &[1_520_000_000_123_456i64, 2_210_000_000_654_321] {
+            assert!((from_ntp(&to_ntp(micros)) - micros).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn skewed_server() {
+        let address = stand_in(|req| reply(req, 5_000_000, 2));
+        let sample = query(&address, StdDuration::from_secs(2)).unwrap();
+
+        assert!((sample.offset - Duration::seconds(5)).num_milliseconds().abs() < 100);
+        assert!(sample.uncertainty >= Duration::milliseconds(9));
+        assert!(sample.uncertainty < Duration::milliseconds(200));
+    }
+
+    #[test]
+    fn bad_replies() {
+        let address = stand_in(|req| reply(req, 0, 0));
+        assert!(query(&address, StdDuration::from_secs(2)).is_err());
+
+        let address = stand_in(|req| {
+            let mut answer = reply(req, 0, 2);
+            answer[31] ^= 1; // originate timestamp
+            answer
+        });
+        assert!(query(&address, StdDuration::from_secs(2)).is_err());
+    }
+}
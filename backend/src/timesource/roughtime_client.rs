@@ -0,0 +1,97 @@
+//! Roughtime client, for servers whose answers are signed
+
+use std::ops::Deref;
+use std::time::Duration as StdDuration;
+
+use chrono::Duration;
+use ring::rand::SecureRandom;
+
+use errors::*;
+use key_types::RANDOM;
+use roughtime::{request, verify_response, NONCE_LEN};
+
+use super::{connect, now_micros, Sample};
+
+/// Replies carry a certificate and a Merkle path, and are well under this
+const MAX_REPLY: usize = 4096;
+
+/// Ask the Roughtime server at `address` for its time. The reply must be
+/// signed by the server's pinned `long_term_key`
+pub fn query(address: &str, long_term_key: &[u8], timeout: StdDuration) -> Result<Sample> {
+    let (socket, server) = connect(address, timeout)?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    RANDOM
+        .deref()
+        .fill(&mut nonce[..])
+        .chain_err(|| "Failed to generate Roughtime nonce")?;
+
+    let t1 = now_micros();
+    socket
+        .send_to(&request(&nonce), server)
+        .chain_err(|| format!("Failed to query {}", address))?;
+
+    let mut buf = vec![0u8; MAX_REPLY];
+    loop {
+        let (n, from) = socket
+            .recv_from(&mut buf)
+            .chain_err(|| format!("No reply from {}", address))?;
+        let t4 = now_micros();
+
+        if from != server {
+            continue;
+        }
+
+        let (midpoint, radius) = verify_response(long_term_key, &nonce, &buf[..n])?;
+
+        // The server read its clock somewhere during the round trip
+        return Ok(Sample {
+            offset: Duration::microseconds(midpoint as i64 - (t1 + t4) / 2),
+            uncertainty: Duration::microseconds(radius as i64 + (t4 - t1) / 2),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use ring::signature::Ed25519KeyPair;
+    use untrusted;
+
+    use super::*;
+    use datetime_utils::ProveWhenTime;
+    use key_types::SingleKeySet;
+    use roughtime::Server;
+
+    #[test]
+    fn stand_in_server() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(RANDOM.deref()).unwrap();
+        let long_term = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8)).unwrap();
+        let long_term_pk = long_term.public_key_bytes().to_vec();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let mut server = Server::new(long_term, 50_000);
+            let online = SingleKeySet::new();
+
+            let mut buf = vec![0u8; 1500];
+            for _ in 0..2 {
+                let (n, from) = socket.recv_from(&mut buf).unwrap();
+                let replies = server.respond(&[buf[..n].to_vec()], &online, &ProveWhenTime::now());
+                socket.send_to(replies[0].as_ref().unwrap(), from).unwrap();
+            }
+        });
+
+        let sample = query(&address, &long_term_pk, StdDuration::from_secs(2)).unwrap();
+        assert!(sample.offset.num_milliseconds().abs() < 100);
+        assert!(sample.uncertainty >= Duration::milliseconds(50));
+
+        // Replies are only trusted from the pinned key
+        let other = SingleKeySet::new();
+        assert!(query(&address, other.public_key_bytes(), StdDuration::from_secs(2)).is_err());
+    }
+}